@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS state
+(
+    key   TEXT PRIMARY KEY NOT NULL,
+    value TEXT             NOT NULL
+);
@@ -0,0 +1,9 @@
+ALTER TABLE plays
+    ADD COLUMN derived_genre TEXT;
+
+CREATE TABLE IF NOT EXISTS artist_genres
+(
+    artist     TEXT PRIMARY KEY NOT NULL,
+    genre      TEXT,
+    fetched_at INTEGER          NOT NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE plays
+    ADD COLUMN source TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_plays_source ON plays (source);
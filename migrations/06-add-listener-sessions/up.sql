@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS listener_sessions
+(
+    id             INTEGER PRIMARY KEY AUTOINCREMENT,
+    source         TEXT,
+    started_at     INTEGER NOT NULL,
+    last_heartbeat INTEGER NOT NULL
+);
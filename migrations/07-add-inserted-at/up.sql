@@ -0,0 +1,4 @@
+-- SQLite can't add a column with a non-constant default, new plays get it set on insert.
+-- It stays NULL for plays recorded before this migration, when it is unknown.
+ALTER TABLE plays
+    ADD COLUMN inserted_at INTEGER;
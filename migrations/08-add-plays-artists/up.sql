@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS plays_artists
+(
+    play_id INTEGER NOT NULL,
+    artist  TEXT    NOT NULL,
+    FOREIGN KEY (play_id) REFERENCES plays (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_plays_artists_play_id ON plays_artists (play_id);
+CREATE INDEX IF NOT EXISTS idx_plays_artists_artist ON plays_artists (artist);
@@ -0,0 +1,2 @@
+ALTER TABLE listener_sessions
+    ADD COLUMN server_version TEXT;
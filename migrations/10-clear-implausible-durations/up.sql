@@ -0,0 +1,5 @@
+-- durations over a day are bogus tags, 0 is the same "unknown" that streams get
+UPDATE plays
+SET song_duration_seconds = 0
+WHERE song_duration_seconds > 86400
+   OR song_duration_seconds < 0;
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS idx_plays_other_tags_play_id ON plays_other_tags (play_id);
@@ -0,0 +1,3 @@
+-- whether MPD's random mode was on when the play started, unknown (NULL) for older plays
+ALTER TABLE plays
+    ADD COLUMN random INTEGER;
@@ -0,0 +1,12 @@
+-- raw statuses read from MPD, only recorded with `listener --debug-record`
+CREATE TABLE IF NOT EXISTS status_snapshots
+(
+    id               INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id       INTEGER NOT NULL REFERENCES listener_sessions (id),
+    timestamp        INTEGER NOT NULL,
+    file             TEXT    NOT NULL,
+    state            TEXT    NOT NULL,
+    random           INTEGER NOT NULL,
+    elapsed_seconds  REAL    NOT NULL,
+    duration_seconds REAL    NOT NULL
+);
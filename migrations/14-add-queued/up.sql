@@ -0,0 +1,15 @@
+-- songs added to the queue, only recorded with `listener --record-queue`; not counted as plays
+CREATE TABLE IF NOT EXISTS queued
+(
+    id                    INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp             INTEGER NOT NULL,
+    file                  TEXT    NOT NULL,
+    title                 TEXT,
+    artist                TEXT,
+    album                 TEXT,
+    album_artist          TEXT,
+    song_duration_seconds INTEGER,
+    source                TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_queued_timestamp ON queued (timestamp);
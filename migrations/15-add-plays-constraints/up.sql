@@ -0,0 +1,62 @@
+-- SQLite can't add constraints to an existing table, so plays is rebuilt with them.
+-- Existing rows are repaired rather than dropped, so all-time stats don't change: timestamps at or
+-- before the epoch become the earliest valid one (1), empty titles and artists become unknown
+-- (NULL), negative durations unknown (0).
+
+-- with foreign keys enforced, dropping plays cascades to these, and the pragma to turn that off
+-- doesn't work within the migration's transaction; set them aside to restore afterwards
+CREATE TEMP TABLE plays_other_tags_backup AS SELECT * FROM plays_other_tags;
+CREATE TEMP TABLE plays_artists_backup AS SELECT * FROM plays_artists;
+
+CREATE TABLE plays_new
+(
+    id                    INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp             INTEGER NOT NULL CHECK (timestamp > 0),
+    title                 TEXT CHECK (title <> ''),
+    artist                TEXT CHECK (artist <> ''),
+    album                 TEXT,
+    album_artist          TEXT,
+    date                  TEXT,
+    song_duration_seconds INTEGER DEFAULT 0 NOT NULL CHECK (song_duration_seconds >= 0),
+    derived_genre         TEXT,
+    source                TEXT,
+    inserted_at           INTEGER,
+    random                INTEGER
+);
+
+INSERT INTO plays_new (id, timestamp, title, artist, album, album_artist, date, song_duration_seconds,
+                       derived_genre, source, inserted_at, random)
+SELECT id,
+       MAX(timestamp, 1),
+       NULLIF(title, ''),
+       NULLIF(artist, ''),
+       album,
+       album_artist,
+       date,
+       MAX(song_duration_seconds, 0),
+       derived_genre,
+       source,
+       inserted_at,
+       random
+FROM plays;
+
+-- keep the ids of plays deleted before from being reused
+UPDATE sqlite_sequence
+SET seq = (SELECT seq FROM sqlite_sequence WHERE name = 'plays')
+WHERE name = 'plays_new';
+
+DROP TABLE plays;
+ALTER TABLE plays_new RENAME TO plays;
+
+DELETE FROM plays_other_tags;
+INSERT INTO plays_other_tags SELECT * FROM plays_other_tags_backup;
+DELETE FROM plays_artists;
+INSERT INTO plays_artists SELECT * FROM plays_artists_backup;
+DROP TABLE plays_other_tags_backup;
+DROP TABLE plays_artists_backup;
+
+CREATE INDEX IF NOT EXISTS idx_plays_timestamp ON plays (timestamp);
+CREATE INDEX IF NOT EXISTS idx_plays_artist ON plays (artist);
+CREATE INDEX IF NOT EXISTS idx_plays_album ON plays (album);
+CREATE INDEX IF NOT EXISTS idx_plays_album_artist ON plays (album_artist);
+CREATE INDEX IF NOT EXISTS idx_plays_source ON plays (source);
@@ -0,0 +1,3 @@
+-- the song's file in MPD's music directory (or its stream URL), unknown (NULL) for older plays
+ALTER TABLE plays
+    ADD COLUMN file TEXT;
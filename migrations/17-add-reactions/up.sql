@@ -0,0 +1,13 @@
+-- loved and banned songs, one reaction per file, only recorded with `listener --record-reactions`
+CREATE TABLE IF NOT EXISTS reactions
+(
+    file                  TEXT    PRIMARY KEY,
+    reaction              TEXT    NOT NULL CHECK (reaction IN ('love', 'ban')),
+    timestamp             INTEGER NOT NULL,
+    title                 TEXT,
+    artist                TEXT,
+    album                 TEXT,
+    album_artist          TEXT,
+    song_duration_seconds INTEGER,
+    source                TEXT
+);
@@ -0,0 +1,9 @@
+-- MPD's play count stickers as of the last `listener --catch-up`, to tell how many plays were
+-- missed since. The source is '' for an unnamed listener.
+CREATE TABLE IF NOT EXISTS playcount_baselines
+(
+    source    TEXT    NOT NULL,
+    file      TEXT    NOT NULL,
+    playcount INTEGER NOT NULL,
+    PRIMARY KEY (source, file)
+);
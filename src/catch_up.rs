@@ -0,0 +1,208 @@
+use crate::mpd::SongListenRecord;
+use crate::persistence::{PlayRecord, PlayStore, TagFilter};
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use tracing::{info, warn};
+
+/// Somewhere to read play count stickers and the songs they belong to from
+pub trait PlaycountSource {
+    /// Every song with a play count sticker, by file, and its count
+    fn playcounts(&mut self) -> Result<Vec<(String, u64)>>;
+
+    /// The song at `file`, `None` if it's not in the library anymore
+    fn song(&mut self, file: &str) -> Result<Option<mpd::Song>>;
+}
+
+/// Reads the play counts MPD clients keep in a song sticker
+pub struct StickerPlaycounts<S: Read + Write> {
+    pub client: mpd::Client<S>,
+    pub sticker: String,
+}
+
+impl<S: Read + Write> PlaycountSource for StickerPlaycounts<S> {
+    fn playcounts(&mut self) -> Result<Vec<(String, u64)>> {
+        let stickers = self
+            .client
+            .find_sticker("song", "", &self.sticker)
+            .with_context(|| {
+                format!(
+                    "Failed to read the {:?} stickers, is MPD's sticker database enabled?",
+                    self.sticker
+                )
+            })?;
+
+        Ok(stickers
+            .into_iter()
+            .filter_map(|(file, count)| match count.parse() {
+                Ok(count) => Some((file, count)),
+                Err(_) => {
+                    warn!("ignoring play count {count:?} of {file:?}");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn song(&mut self, file: &str) -> Result<Option<mpd::Song>> {
+        let path = mpd::Song {
+            file: file.to_string(),
+            ..Default::default()
+        };
+        match self.client.lsinfo(&path) {
+            Ok(songs) => Ok(songs.into_iter().next()),
+            Err(mpd::error::Error::Server(_)) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to look up {file:?}")),
+        }
+    }
+}
+
+/// The source backfilled plays are recorded under, to tell them apart from plays the listener saw
+pub fn backfill_source(source: Option<&str>) -> String {
+    match source {
+        Some(source) => format!("{source} (backfill)"),
+        None => "backfill".to_string(),
+    }
+}
+
+/// Log the plays that the play count stickers went up by since the last catch-up, but that weren't
+/// recorded, e.g. while the listener was down. Returns the number of plays backfilled.
+///
+/// When and how far a song was played isn't known, so backfilled plays are timestamped `now`, count
+/// the whole song, and are recorded under [`backfill_source`]. The first catch-up only notes the
+/// play counts to compare against next time.
+pub fn catch_up(
+    store: &dyn PlayStore,
+    playcounts: &mut dyn PlaycountSource,
+    filter: &TagFilter,
+    source: Option<&str>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<usize> {
+    let counts = playcounts.playcounts()?;
+
+    let mut backfilled = Vec::new();
+    if let Some((taken_at, previous)) = store.playcount_baseline(source)? {
+        let recorded = store.plays_by_file_since(source, taken_at)?;
+
+        for (file, count) in &counts {
+            // a song without a sticker then hadn't been played yet
+            let played = count.saturating_sub(previous.get(file).copied().unwrap_or(0));
+            let missed = played.saturating_sub(recorded.get(file).copied().unwrap_or(0));
+            if missed == 0 {
+                continue;
+            }
+
+            let Some(song) = playcounts.song(file)? else {
+                warn!("{file:?} is no longer in the library, not backfilling its {missed} plays");
+                continue;
+            };
+            let listen = SongListenRecord {
+                song,
+                start: now,
+                random: false,
+                duration: None,
+            };
+            let record = PlayRecord {
+                source: Some(backfill_source(source)),
+                random: None,
+                ..PlayRecord::from_listen(listen, filter)
+            };
+            info!("backfilling {missed} plays of {file:?}");
+            backfilled.extend((0..missed).map(|_| record.clone()));
+        }
+    }
+
+    store.record_catch_up(source, now.timestamp(), &counts, &backfilled)?;
+    Ok(backfilled.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{MusicDb, TimeInterval};
+    use std::collections::HashMap;
+
+    struct FakeStickers {
+        counts: HashMap<String, u64>,
+    }
+
+    impl PlaycountSource for FakeStickers {
+        fn playcounts(&mut self) -> Result<Vec<(String, u64)>> {
+            let mut counts: Vec<_> = self.counts.clone().into_iter().collect();
+            counts.sort();
+            Ok(counts)
+        }
+
+        fn song(&mut self, file: &str) -> Result<Option<mpd::Song>> {
+            Ok((file != "deleted.flac").then(|| mpd::Song {
+                file: file.to_string(),
+                title: Some(file.trim_end_matches(".flac").to_string()),
+                artist: Some("Artist".to_string()),
+                duration: Some(std::time::Duration::from_secs(180)),
+                ..Default::default()
+            }))
+        }
+    }
+
+    fn at(timestamp: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    #[test]
+    fn test_catch_up() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let store: &dyn PlayStore = &db;
+        let filter = TagFilter::default();
+        let mut stickers = FakeStickers {
+            counts: HashMap::from([("a.flac".to_string(), 40), ("b.flac".to_string(), 3)]),
+        };
+
+        // the first catch-up can't tell which plays were missed
+        assert_eq!(
+            catch_up(store, &mut stickers, &filter, None, at(1702800000))?,
+            0
+        );
+
+        // one play of a.flac was recorded, three happened, and c.flac was played for the first time
+        store.log_play(&PlayRecord {
+            timestamp: 1702800100,
+            title: Some("a".to_string()),
+            artist: Some("Artist".to_string()),
+            album: None,
+            album_artist: None,
+            date: None,
+            other_tags: Default::default(),
+            song_duration_seconds: Some(180),
+            source: None,
+            random: None,
+            file: Some("a.flac".to_string()),
+        })?;
+        stickers.counts.insert("a.flac".to_string(), 43);
+        stickers.counts.insert("c.flac".to_string(), 1);
+        stickers.counts.insert("deleted.flac".to_string(), 2);
+
+        assert_eq!(
+            catch_up(store, &mut stickers, &filter, None, at(1702900000))?,
+            3
+        );
+        let backfilled = store.get_top_songs(TimeInterval::AllTime, Some("backfill"), None)?;
+        let backfilled: Vec<_> = backfilled
+            .iter()
+            .map(|song| (song.title.as_str(), song.play_count))
+            .collect();
+        assert_eq!(backfilled, vec![("a", 2), ("c", 1)]);
+
+        // nothing changed since
+        assert_eq!(
+            catch_up(store, &mut stickers, &filter, None, at(1703000000))?,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backfill_source() {
+        assert_eq!(backfill_source(None), "backfill");
+        assert_eq!(backfill_source(Some("office")), "office (backfill)");
+    }
+}
@@ -0,0 +1,192 @@
+use crate::persistence::{PlayStore, TimeInterval};
+use std::fmt::Write;
+use std::str::FromStr;
+
+const WIDTH: usize = 800;
+const LABEL_WIDTH: usize = 280;
+/// Room right of the longest bar for its value
+const VALUE_WIDTH: usize = 90;
+const TITLE_HEIGHT: usize = 40;
+const BAR_HEIGHT: usize = 22;
+const BAR_GAP: usize = 8;
+/// Labels longer than this many characters are cut, to stay left of the bars
+const MAX_LABEL_CHARS: usize = 38;
+
+/// Which top list to chart
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ChartStat {
+    #[default]
+    Artists,
+    Songs,
+    Albums,
+}
+
+impl FromStr for ChartStat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "artists" => Ok(ChartStat::Artists),
+            "songs" => Ok(ChartStat::Songs),
+            "albums" => Ok(ChartStat::Albums),
+            _ => Err(format!("expected artists, songs or albums, not {s:?}")),
+        }
+    }
+}
+
+/// Render the top list of `stat` as an SVG bar chart of minutes listened
+pub fn render(
+    db: &dyn PlayStore,
+    stat: ChartStat,
+    interval: TimeInterval,
+    interval_name: &str,
+    source: Option<&str>,
+    split_artists: bool,
+    limit: Option<usize>,
+) -> crate::error::Result<String> {
+    let (title, bars): (_, Vec<_>) = match stat {
+        ChartStat::Artists => (
+            "Top Artists",
+            if split_artists {
+                db.get_top_split_artists(interval, source, limit)?
+            } else {
+                db.get_top_artists(interval, source, limit)?
+            }
+            .into_iter()
+            .map(|artist| (artist.artist_name, artist.total_minutes))
+            .collect(),
+        ),
+        ChartStat::Songs => (
+            "Top Songs",
+            db.get_top_songs(interval, source, limit)?
+                .into_iter()
+                .map(|song| {
+                    (
+                        format!("{} by {}", song.title, song.artist_name),
+                        song.total_minutes,
+                    )
+                })
+                .collect(),
+        ),
+        ChartStat::Albums => (
+            "Top Albums",
+            db.get_top_albums(interval, source, limit)?
+                .into_iter()
+                .map(|album| {
+                    (
+                        format!("{} by {}", album.album, album.artist_name),
+                        album.total_minutes,
+                    )
+                })
+                .collect(),
+        ),
+    };
+
+    Ok(bar_chart(&format!("{title} ({interval_name})"), &bars))
+}
+
+/// Lay out labeled minutes as horizontal bars scaled to the largest value
+pub fn bar_chart(title: &str, bars: &[(String, f64)]) -> String {
+    let height = TITLE_HEIGHT + bars.len() * (BAR_HEIGHT + BAR_GAP) + BAR_GAP;
+    let max_bar_width = (WIDTH - LABEL_WIDTH - VALUE_WIDTH) as f64;
+    let max_minutes = bars.iter().map(|(_, minutes)| *minutes).fold(0.0, f64::max);
+
+    let mut svg = String::new();
+    // writing to a String can't fail
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{height}" viewBox="0 0 {WIDTH} {height}" font-family="sans-serif" font-size="13">"#
+    );
+    let _ = writeln!(
+        svg,
+        r#"<text x="10" y="26" font-size="17" font-weight="bold">{}</text>"#,
+        escape(title)
+    );
+
+    for (i, (label, minutes)) in bars.iter().enumerate() {
+        let y = TITLE_HEIGHT + i * (BAR_HEIGHT + BAR_GAP);
+        let middle = y + BAR_HEIGHT / 2;
+        let width = if max_minutes > 0.0 {
+            minutes / max_minutes * max_bar_width
+        } else {
+            0.0
+        };
+        let minutes = minutes.round() as i64;
+
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{middle}" text-anchor="end" dominant-baseline="middle">{}</text>"#,
+            LABEL_WIDTH - 8,
+            escape(&shorten(label))
+        );
+        let _ = writeln!(
+            svg,
+            r##"<rect x="{LABEL_WIDTH}" y="{y}" width="{width:.1}" height="{BAR_HEIGHT}" fill="#4c78a8"><title>{}: {minutes} minutes</title></rect>"##,
+            escape(label)
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{:.1}" y="{middle}" dominant-baseline="middle">{minutes} min</text>"#,
+            LABEL_WIDTH as f64 + width + 6.0
+        );
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Cut `label` to [`MAX_LABEL_CHARS`], ending in an ellipsis if it was cut. The full label is
+/// kept in the bar's tooltip.
+fn shorten(label: &str) -> String {
+    if label.chars().count() <= MAX_LABEL_CHARS {
+        return label.to_string();
+    }
+    let mut shortened: String = label.chars().take(MAX_LABEL_CHARS - 1).collect();
+    shortened.truncate(shortened.trim_end().len());
+    shortened.push('…');
+    shortened
+}
+
+/// Escape text for use in SVG content and attributes
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bar_chart() {
+        let svg = bar_chart(
+            "Top Artists (Week)",
+            &[
+                ("Simon & Garfunkel".to_string(), 120.4),
+                ("<Unknown>".to_string(), 60.2),
+            ],
+        );
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains(">Top Artists (Week)</text>"));
+        assert!(svg.contains(">Simon &amp; Garfunkel</text>"));
+        assert!(svg.contains(">&lt;Unknown&gt;</text>"));
+        assert!(svg.contains(">120 min</text>"));
+
+        // the largest value takes the full width, the rest scale to it
+        assert!(svg.contains(r#"width="430.0""#));
+        assert!(svg.contains(r#"width="215.0""#));
+    }
+
+    #[test]
+    fn test_shorten() {
+        assert_eq!(shorten("Radiohead"), "Radiohead");
+        let long = "A".repeat(MAX_LABEL_CHARS + 5);
+        let shortened = shorten(&long);
+        assert_eq!(shortened.chars().count(), MAX_LABEL_CHARS);
+        assert!(shortened.ends_with('…'));
+    }
+}
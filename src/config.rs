@@ -0,0 +1,203 @@
+use crate::error::{Error, Result};
+use crate::export::TimestampPrecision;
+use crate::mpd::ThresholdMode;
+use crate::persistence::{Reaction, TagFilter};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// User configuration, read from `config.toml` (or `config.json`) in the config directory.
+///
+/// Every setting is optional, a missing file is the same as an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Timezone for telling which day a play fell on, the system's if unset
+    pub timezone: Option<chrono_tz::Tz>,
+    pub artists: ArtistsConfig,
+    pub listener: ListenerConfig,
+    pub tags: TagFilter,
+    pub export: ExportConfig,
+    pub reactions: ReactionsConfig,
+}
+
+/// How MPD clients rate songs, for `listener --record-reactions`
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReactionsConfig {
+    /// The song sticker holding the rating
+    pub sticker: String,
+    /// The sticker value for a loved song
+    pub love: String,
+    /// The sticker value for a banned song
+    pub ban: String,
+}
+
+impl Default for ReactionsConfig {
+    fn default() -> Self {
+        // myMPD's "like" sticker
+        Self {
+            sticker: "like".to_string(),
+            love: "2".to_string(),
+            ban: "0".to_string(),
+        }
+    }
+}
+
+impl ReactionsConfig {
+    /// The reaction a sticker value stands for, `None` for anything else (e.g. neutral)
+    pub fn reaction(&self, value: Option<&str>) -> Option<Reaction> {
+        match value? {
+            value if value == self.love => Some(Reaction::Love),
+            value if value == self.ban => Some(Reaction::Ban),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    /// Round exported timestamps down to this, for sharing exports without exact times
+    pub timestamp_precision: TimestampPrecision,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Don't count songs that were already playing when first seen, e.g. at startup
+    pub require_start: bool,
+    /// Don't track songs shorter than this at all
+    pub min_track_length_seconds: u64,
+    /// How far into a song playback must get before jumping back to its start counts as a restart
+    pub restart_threshold_seconds: u64,
+    /// How close to the start a jump back must land to count as a restart
+    pub restart_window_seconds: u64,
+    /// Whether a song counts once played long enough or far enough through (`any`), or only once
+    /// both (`all`)
+    pub threshold_mode: ThresholdMode,
+    /// The song sticker MPD clients keep play counts in, for `listener --catch-up`
+    pub playcount_sticker: String,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            require_start: false,
+            min_track_length_seconds: 0,
+            restart_threshold_seconds: 5,
+            restart_window_seconds: 5,
+            threshold_mode: ThresholdMode::default(),
+            // myMPD's
+            playcount_sticker: "playCount".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArtistsConfig {
+    /// Credit each artist of a multi-artist tag ("A feat. B") separately
+    pub split: bool,
+    /// What separates the artists in a credit
+    pub separators: Vec<String>,
+}
+
+impl Default for ArtistsConfig {
+    fn default() -> Self {
+        Self {
+            split: false,
+            separators: ["feat.", "&", "/", ","].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read {path:?}"), Some(e.into())))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| {
+                Error::Config(format!("Failed to parse {path:?} as TOML"), Some(e.into()))
+            }),
+            Some("json") => serde_json::from_str(&contents).map_err(|e| {
+                Error::Config(format!("Failed to parse {path:?} as JSON"), Some(e.into()))
+            }),
+            _ => Err(Error::Config(
+                format!("Unsupported config file {path:?}, expected a .toml or .json file"),
+                None,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> anyhow::Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            timezone = "Europe/Berlin"
+
+            [artists]
+            split = true
+
+            [listener]
+            threshold_mode = "all"
+            restart_threshold_seconds = 30
+
+            [reactions]
+            ban = "-1"
+            "#,
+        )?;
+        assert_eq!(config.timezone, Some(chrono_tz::Europe::Berlin));
+        assert!(config.artists.split);
+        assert_eq!(config.artists.separators.len(), 4);
+        assert_eq!(config.listener.threshold_mode, ThresholdMode::All);
+        assert_eq!(config.listener.restart_threshold_seconds, 30);
+        assert_eq!(config.listener.restart_window_seconds, 5);
+        assert_eq!(config.reactions.reaction(Some("2")), Some(Reaction::Love));
+        assert_eq!(config.reactions.reaction(Some("1")), None);
+        assert_eq!(config.reactions.reaction(Some("-1")), Some(Reaction::Ban));
+        assert_eq!(config.reactions.reaction(None), None);
+
+        assert!(toml::from_str::<Config>("[artist]\nsplit = true").is_err());
+        assert!(toml::from_str::<Config>("timezone = \"Mars/Olympus\"").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_by_extension() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mpd-wrapped-config-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        let json = dir.join("config.json");
+        fs::write(
+            &json,
+            r#"{"artists": {"split": true, "separators": [" x "]}}"#,
+        )?;
+        let config = Config::load(&json)?;
+        assert!(config.artists.split);
+        assert_eq!(config.artists.separators, vec![" x "]);
+
+        // TOML in a .json file is reported as invalid JSON
+        fs::write(&json, "[artists]\nsplit = true")?;
+        let error = Config::load(&json).unwrap_err();
+        assert!(matches!(error, Error::Config(..)));
+        assert!(error.to_string().contains("as JSON"), "{error}");
+
+        let yaml = dir.join("config.yaml");
+        fs::write(&yaml, "artists:\n  split: true")?;
+        assert!(Config::load(&yaml).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use crate::config::Config;
+use crate::persistence::MusicDb;
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait for MPD to answer once connected
+const MPD_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Plays older than this suggest the listener isn't running
+const RECENT_PLAY_AGE: i64 = 7 * 24 * 60 * 60;
+
+enum Outcome {
+    Pass(String),
+    Fail { problem: String, hint: String },
+}
+
+fn fail(problem: impl Into<String>, hint: impl Into<String>) -> Outcome {
+    Outcome::Fail {
+        problem: problem.into(),
+        hint: hint.into(),
+    }
+}
+
+/// Check that everything the listener and queries depend on is in place, printing a checklist.
+///
+/// Fails if any check did, so scripts can rely on the exit status.
+pub fn run(
+    config_path: &Path,
+    db_path: &Path,
+    mpd_address: &str,
+    connect_timeout: Duration,
+) -> Result<()> {
+    let mut failures = 0;
+    let mut report = |name: &str, outcome: Outcome| match outcome {
+        Outcome::Pass(detail) => println!("PASS  {name}: {detail}"),
+        Outcome::Fail { problem, hint } => {
+            failures += 1;
+            println!("FAIL  {name}: {problem}");
+            println!("      {hint}");
+        }
+    };
+
+    report("Config", check_config(config_path));
+
+    let db = match MusicDb::new(db_path) {
+        Ok(db) => Some(db),
+        Err(e) => {
+            report(
+                "Database",
+                fail(
+                    format!("could not open {db_path:?}: {e}"),
+                    "Check that the data directory exists and is writable.",
+                ),
+            );
+            None
+        }
+    };
+    if let Some(db) = &db {
+        report("Database", check_database(db, db_path));
+    }
+
+    report("MPD", check_mpd(mpd_address, connect_timeout));
+
+    if let Some(db) = &db {
+        report("Recent plays", check_recent_plays(db));
+    }
+
+    if failures > 0 {
+        bail!("{failures} check(s) failed");
+    }
+    Ok(())
+}
+
+fn check_config(path: &Path) -> Outcome {
+    if !path.exists() {
+        return Outcome::Pass(format!("{path:?} not found, using defaults"));
+    }
+    match Config::load(path) {
+        Ok(_) => Outcome::Pass(format!("{path:?} parsed")),
+        Err(e) => fail(
+            format!("{e:#}"),
+            "Fix the setting mentioned above, or remove it to use the default.",
+        ),
+    }
+}
+
+fn check_database(db: &MusicDb, path: &Path) -> Outcome {
+    match (db.is_read_only(), db.schema_version()) {
+        (Ok(false), Ok(version)) => {
+            Outcome::Pass(format!("{path:?} is writable, schema version {version}"))
+        }
+        (Ok(true), _) => fail(
+            format!("{path:?} is read-only"),
+            "Check the permissions of the database file and its directory.",
+        ),
+        (Err(e), _) | (_, Err(e)) => fail(
+            format!("could not inspect {path:?}: {e}"),
+            "The file may be corrupt or not an mpd-wrapped database.",
+        ),
+    }
+}
+
+fn check_mpd(address: &str, connect_timeout: Duration) -> Outcome {
+    let hint = "Is MPD running? Pass --mpd <address> if it listens somewhere else.";
+    match describe_mpd(address, connect_timeout) {
+        Ok(server) => Outcome::Pass(format!("connected to {address}, {server}")),
+        Err(e) => fail(format!("{e:#}"), hint),
+    }
+}
+
+/// Connect and describe the server, without waiting long on an unresponsive one
+fn describe_mpd(address: &str, connect_timeout: Duration) -> Result<String> {
+    let stream = crate::mpd::connect(address, connect_timeout)?;
+    stream.set_read_timeout(Some(MPD_TIMEOUT))?;
+    stream.set_write_timeout(Some(MPD_TIMEOUT))?;
+
+    let mut client = ::mpd::Client::new(stream)
+        .with_context(|| format!("{address} did not respond like an MPD server"))?;
+    let status = client
+        .status()
+        .context("could not read the player status")?;
+    let state = match status.state {
+        ::mpd::State::Play => "playing",
+        ::mpd::State::Pause => "paused",
+        ::mpd::State::Stop => "stopped",
+    };
+    Ok(format!(
+        "MPD {}, player is {state}",
+        crate::mpd::format_version(client.version)
+    ))
+}
+
+fn check_recent_plays(db: &MusicDb) -> Outcome {
+    let hint = "Is the listener running? See the README for running it as a service.";
+    match db.latest_play_timestamp() {
+        Ok(Some(timestamp)) => {
+            let age = chrono::Utc::now().timestamp() - timestamp;
+            if age <= RECENT_PLAY_AGE {
+                Outcome::Pass(format!("last play recorded {} ago", format_age(age)))
+            } else {
+                fail(
+                    format!(
+                        "no plays recorded in the last {}",
+                        format_age(RECENT_PLAY_AGE)
+                    ),
+                    hint,
+                )
+            }
+        }
+        Ok(None) => fail("no plays recorded yet", hint),
+        Err(e) => fail(
+            format!("could not read plays: {e}"),
+            "The database may be corrupt.",
+        ),
+    }
+}
+
+fn format_age(seconds: i64) -> String {
+    match seconds {
+        s if s < 60 * 60 => format!("{} minutes", s / 60),
+        s if s < 24 * 60 * 60 => format!("{} hours", s / (60 * 60)),
+        s => format!("{} days", s / (24 * 60 * 60)),
+    }
+}
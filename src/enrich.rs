@@ -0,0 +1,192 @@
+use crate::persistence::PlayStore;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Somewhere to look up an artist's genre
+pub trait GenreSource {
+    fn lookup(&mut self, artist: &str) -> Result<Option<String>>;
+}
+
+/// Looks up genres from the most voted tag of the best matching MusicBrainz artist
+pub struct MusicBrainz {
+    agent: ureq::Agent,
+    last_request: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct ArtistSearch {
+    artists: Vec<Artist>,
+}
+
+#[derive(Deserialize)]
+struct Artist {
+    score: u32,
+    #[serde(default)]
+    tags: Vec<Tag>,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    count: i64,
+    name: String,
+}
+
+impl MusicBrainz {
+    const SEARCH_URL: &str = "https://musicbrainz.org/ws/2/artist/";
+    /// MusicBrainz allows one request per second
+    const MIN_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+    /// Search scores are out of 100, anything less is likely a different artist
+    const MIN_SCORE: u32 = 90;
+
+    pub fn new() -> Self {
+        Self {
+            agent: ureq::Agent::new_with_defaults(),
+            last_request: None,
+        }
+    }
+
+    fn wait_for_rate_limit(&mut self) {
+        if let Some(last_request) = self.last_request {
+            let elapsed = last_request.elapsed();
+            if elapsed < Self::MIN_REQUEST_INTERVAL {
+                thread::sleep(Self::MIN_REQUEST_INTERVAL - elapsed);
+            }
+        }
+        self.last_request = Some(Instant::now());
+    }
+}
+
+impl GenreSource for MusicBrainz {
+    fn lookup(&mut self, artist: &str) -> Result<Option<String>> {
+        self.wait_for_rate_limit();
+
+        let query = format!("artist:\"{}\"", artist.replace('"', "\\\""));
+        let search: ArtistSearch = self
+            .agent
+            .get(Self::SEARCH_URL)
+            .header(
+                "User-Agent",
+                concat!("mpd-wrapped/", env!("CARGO_PKG_VERSION")),
+            )
+            .query("query", &query)
+            .query("limit", "1")
+            .query("fmt", "json")
+            .call()
+            .with_context(|| format!("MusicBrainz lookup for {artist:?} failed"))?
+            .body_mut()
+            .read_json()
+            .context("Unexpected response from MusicBrainz")?;
+
+        let genre = search
+            .artists
+            .into_iter()
+            .find(|a| a.score >= Self::MIN_SCORE)
+            .and_then(|a| a.tags.into_iter().max_by_key(|tag| tag.count))
+            .map(|tag| tag.name);
+
+        Ok(genre)
+    }
+}
+
+/// Derive genres for plays that lack a Genre tag, looking up each artist at most once.
+/// Returns the number of plays that got a genre.
+pub fn enrich_genres(store: &dyn PlayStore, source: &mut dyn GenreSource) -> Result<usize> {
+    let mut updated = 0;
+
+    for artist in store.artists_missing_genre()? {
+        let genre = match store.cached_artist_genre(&artist)? {
+            Some(cached) => cached,
+            None => match source.lookup(&artist) {
+                Ok(genre) => {
+                    store.cache_artist_genre(&artist, genre.as_deref())?;
+                    genre
+                }
+                Err(e) => {
+                    // try again next time
+                    warn!("{e:#}");
+                    continue;
+                }
+            },
+        };
+
+        if let Some(genre) = genre {
+            let count = store.set_derived_genre(&artist, &genre)?;
+            info!("derived genre {genre:?} for {count} plays by {artist:?}");
+            updated += count;
+        }
+    }
+
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{MusicDb, PlayRecord, TimeInterval};
+    use std::collections::HashMap;
+
+    struct FakeSource {
+        lookups: Vec<String>,
+    }
+
+    impl GenreSource for FakeSource {
+        fn lookup(&mut self, artist: &str) -> Result<Option<String>> {
+            self.lookups.push(artist.to_string());
+            Ok((artist == "Tagless").then(|| "Shoegaze".to_string()))
+        }
+    }
+
+    fn record(artist: &str, genre: Option<&str>) -> PlayRecord {
+        let mut other_tags = HashMap::new();
+        if let Some(genre) = genre {
+            other_tags.insert("Genre".to_string(), vec![genre.to_string()]);
+        }
+
+        PlayRecord {
+            timestamp: 1702800000,
+            title: Some("Song".to_string()),
+            artist: Some(artist.to_string()),
+            album: None,
+            album_artist: None,
+            date: None,
+            other_tags,
+            song_duration_seconds: Some(120),
+            source: None,
+            random: None,
+            file: None,
+        }
+    }
+
+    #[test]
+    fn test_enrich_genres() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let store: &dyn PlayStore = &db;
+        store.log_plays(&[
+            record("Tagged", Some("Rock")),
+            record("Tagless", None),
+            record("Tagless", None),
+            record("Unknown", None),
+        ])?;
+
+        let mut source = FakeSource { lookups: vec![] };
+        assert_eq!(enrich_genres(store, &mut source)?, 2);
+        assert_eq!(source.lookups, vec!["Tagless", "Unknown"]);
+
+        // artists are only looked up once, even when nothing was found
+        assert_eq!(enrich_genres(store, &mut source)?, 0);
+        assert_eq!(source.lookups.len(), 2);
+
+        let genres = store.get_top_genres(TimeInterval::AllTime, None, None)?;
+        assert_eq!(genres.len(), 2);
+        assert_eq!(genres[0].genre, "Shoegaze");
+        assert!(genres[0].inferred);
+        assert_eq!(genres[0].play_count, 2);
+        assert_eq!(genres[1].genre, "Rock");
+        assert!(!genres[1].inferred);
+
+        Ok(())
+    }
+}
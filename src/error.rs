@@ -0,0 +1,28 @@
+use std::io;
+
+/// Errors of the MPD, storage and configuration APIs, so callers can tell them apart.
+///
+/// The CLI itself reports them through `anyhow`, with the same messages as before.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// MPD could not be reached
+    #[error("{0}")]
+    Connection(String, #[source] Option<io::Error>),
+    /// MPD answered with something unexpected
+    #[error(transparent)]
+    Protocol(#[from] ::mpd::error::Error),
+    #[error(transparent)]
+    Database(#[from] rusqlite::Error),
+    #[error("Failed to migrate the database")]
+    Migration(#[from] rusqlite_migration::Error),
+    /// The config file could not be read or is invalid
+    #[error("{0}")]
+    Config(
+        String,
+        #[source] Option<Box<dyn std::error::Error + Send + Sync>>,
+    ),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
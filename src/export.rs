@@ -0,0 +1,183 @@
+use crate::persistence::PlayRecord;
+use serde::Deserialize;
+use std::io::{Result, Write};
+use std::str::FromStr;
+
+const CSV_HEADER: &str = "timestamp,title,artist,album,album_artist,date,song_duration_seconds";
+
+/// How precisely play timestamps are exported, coarser ones are rounded down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampPrecision {
+    #[default]
+    Second,
+    Minute,
+    Hour,
+}
+
+impl TimestampPrecision {
+    pub fn round(self, timestamp: i64) -> i64 {
+        let step = match self {
+            TimestampPrecision::Second => 1,
+            TimestampPrecision::Minute => 60,
+            TimestampPrecision::Hour => 60 * 60,
+        };
+        timestamp - timestamp.rem_euclid(step)
+    }
+}
+
+impl FromStr for TimestampPrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "second" => Ok(TimestampPrecision::Second),
+            "minute" => Ok(TimestampPrecision::Minute),
+            "hour" => Ok(TimestampPrecision::Hour),
+            _ => Err(format!("expected second, minute or hour, not {s:?}")),
+        }
+    }
+}
+
+/// Writes plays as CSV one at a time, preceded by a comment line describing what was exported
+/// and when
+pub struct CsvWriter<W: Write> {
+    out: W,
+    rows: usize,
+    precision: TimestampPrecision,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(mut out: W, description: &str) -> Result<Self> {
+        writeln!(
+            out,
+            "# mpd-wrapped export: {}, generated {}",
+            description,
+            chrono::Local::now().to_rfc3339()
+        )?;
+        writeln!(out, "{CSV_HEADER}")?;
+
+        Ok(Self {
+            out,
+            rows: 0,
+            precision: TimestampPrecision::default(),
+        })
+    }
+
+    /// Round the exported timestamps down to `precision`
+    pub fn with_precision(mut self, precision: TimestampPrecision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn write(&mut self, play: &PlayRecord) -> Result<()> {
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{}",
+            self.precision.round(play.timestamp),
+            csv_field(play.title.as_deref()),
+            csv_field(play.artist.as_deref()),
+            csv_field(play.album.as_deref()),
+            csv_field(play.album_artist.as_deref()),
+            csv_field(play.date.as_deref()),
+            play.song_duration_seconds
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+        )?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Flush the output, returning the number of plays written
+    pub fn finish(mut self) -> Result<usize> {
+        self.out.flush()?;
+        Ok(self.rows)
+    }
+}
+
+fn csv_field(value: Option<&str>) -> String {
+    match value {
+        Some(value) if value.contains([',', '"', '\n', '\r']) => {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        }
+        Some(value) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_csv() -> Result<()> {
+        let play = PlayRecord {
+            timestamp: 1702800000,
+            title: Some("Hello, \"World\"".to_string()),
+            artist: Some("Test Artist".to_string()),
+            album: None,
+            album_artist: None,
+            date: Some("2023".to_string()),
+            other_tags: Default::default(),
+            song_duration_seconds: Some(180),
+            source: None,
+            random: None,
+            file: None,
+        };
+
+        let mut out = Vec::new();
+        let mut writer = CsvWriter::new(&mut out, "Week")?;
+        writer.write(&play)?;
+        assert_eq!(writer.finish()?, 1);
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+
+        assert!(lines[0].starts_with("# mpd-wrapped export: Week, generated "));
+        assert_eq!(lines[1], CSV_HEADER);
+        assert_eq!(
+            lines[2],
+            "1702800000,\"Hello, \"\"World\"\"\",Test Artist,,,2023,180"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_precision() -> Result<()> {
+        // 2023-12-17 07:59:59 UTC, a second before the hour
+        let timestamp = 1702799999;
+        assert_eq!(TimestampPrecision::Second.round(timestamp), timestamp);
+        assert_eq!(TimestampPrecision::Minute.round(timestamp), 1702799940);
+        assert_eq!(TimestampPrecision::Hour.round(timestamp), 1702796400);
+
+        // on the hour nothing changes, a second later is still the same bucket
+        assert_eq!(TimestampPrecision::Hour.round(1702800000), 1702800000);
+        assert_eq!(TimestampPrecision::Hour.round(1702800001), 1702800000);
+        assert_eq!(TimestampPrecision::Minute.round(1702800059), 1702800000);
+
+        assert_eq!("hour".parse(), Ok(TimestampPrecision::Hour));
+        assert!("day".parse::<TimestampPrecision>().is_err());
+
+        // the writer rounds what it exports
+        let mut out = Vec::new();
+        let mut writer = CsvWriter::new(&mut out, "Week")?.with_precision(TimestampPrecision::Hour);
+        writer.write(&PlayRecord {
+            timestamp: 1702800000 + 59 * 60,
+            title: Some("Song".to_string()),
+            artist: Some("Artist".to_string()),
+            album: None,
+            album_artist: None,
+            date: None,
+            other_tags: Default::default(),
+            song_duration_seconds: None,
+            source: None,
+            random: None,
+            file: None,
+        })?;
+        writer.finish()?;
+        let csv = String::from_utf8(out).unwrap();
+        assert_eq!(csv.lines().nth(2), Some("1702800000,Song,Artist,,,,"));
+
+        Ok(())
+    }
+}
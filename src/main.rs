@@ -1,14 +1,34 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use directories::ProjectDirs;
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::fs;
+use std::io::{self, IsTerminal};
 use std::path::PathBuf;
-use tracing::info;
-use crate::persistence::MusicDb;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use tracing::{info, warn};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
+mod catch_up;
+#[cfg(feature = "svg")]
+mod chart;
+mod config;
+mod doctor;
+mod enrich;
+mod error;
+mod export;
 mod mpd;
 mod persistence;
+mod playlist;
 
-use crate::persistence::sqlite::TimeInterval;
+use crate::config::Config;
+use crate::persistence::model::{ArtistComparison, ArtistStanding};
+use crate::persistence::{
+    BufferConfig, MusicDb, PlayRecord, PlayStore, QueuedRecord, ReactionRecord, TimeInterval,
+};
 
 pub fn get_db_path() -> Result<PathBuf> {
     let proj_dirs = ProjectDirs::from("", "", "mpd-wrapped")
@@ -21,6 +41,54 @@ pub fn get_db_path() -> Result<PathBuf> {
     Ok(data_dir.join("music.db"))
 }
 
+/// Each source gets its own lock, so listeners for different MPD instances can run side by side
+pub fn get_lock_path(source: Option<&str>) -> Result<PathBuf> {
+    let file_name = match source {
+        Some(source) => {
+            let source: String = source
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            format!("listener-{source}.lock")
+        }
+        None => "listener.lock".to_string(),
+    };
+
+    Ok(get_db_path()?.with_file_name(file_name))
+}
+
+/// Take an advisory lock so only one listener records plays at a time. The lock is released
+/// when the returned file is dropped, or by the OS if the process dies.
+fn acquire_listener_lock(source: Option<&str>, force: bool) -> Result<fs::File> {
+    let lock_path = get_lock_path(source)?;
+    let lock_file = fs::File::create(&lock_path)
+        .with_context(|| format!("Failed to create lock file {lock_path:?}"))?;
+
+    match lock_file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) if force => {
+            warn!("another listener holds {lock_path:?}, starting anyway because of --force");
+        }
+        Err(fs::TryLockError::WouldBlock) => {
+            bail!(
+                "Another listener is already running (lock held on {lock_path:?}). \
+                 Use --force to start anyway."
+            );
+        }
+        Err(fs::TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Failed to lock {lock_path:?}"));
+        }
+    }
+
+    Ok(lock_file)
+}
+
 pub fn get_config_path() -> Result<PathBuf> {
     let proj_dirs = ProjectDirs::from("", "", "mpd-wrapped")
         .context("Could not determine project directories")?;
@@ -29,115 +97,1169 @@ pub fn get_config_path() -> Result<PathBuf> {
     let config_dir = proj_dirs.config_dir();
     fs::create_dir_all(config_dir).context("Failed to create config directory")?;
 
-    Ok(config_dir.join("config.toml"))
+    // TOML is preferred, JSON is for configs generated by other tools
+    let json_path = config_dir.join("config.json");
+    let toml_path = config_dir.join("config.toml");
+    if json_path.exists() && !toml_path.exists() {
+        return Ok(json_path);
+    }
+    Ok(toml_path)
 }
 
-fn print_stats(db: &MusicDb, interval: TimeInterval) -> Result<()> {
-    let interval_name = match interval {
+fn interval_name(interval: TimeInterval) -> &'static str {
+    match interval {
         TimeInterval::Week => "Week",
         TimeInterval::Month => "Month",
         TimeInterval::Year => "Year",
         TimeInterval::AllTime => "All Time",
+        TimeInterval::Since(_) => "Since Last Query",
+    }
+}
+
+fn parse_interval(pargs: &mut pico_args::Arguments) -> TimeInterval {
+    if pargs.contains("--week") {
+        TimeInterval::Week
+    } else if pargs.contains("--month") {
+        TimeInterval::Month
+    } else if pargs.contains("--year") {
+        TimeInterval::Year
+    } else if pargs.contains("--all") {
+        TimeInterval::AllTime
+    } else {
+        // Default to all time if no flag specified
+        TimeInterval::AllTime
+    }
+}
+
+/// Parse a `YYYY-MM-DD` date into the Unix timestamp of local midnight at its start
+fn parse_date(date: &str) -> Result<i64> {
+    midnight(parse_naive_date(date)?, chrono::Local)
+}
+
+/// Parse a `YYYY-MM-DD` date into the Unix timestamp of local midnight at its end, i.e. the start
+/// of the next day. Days aren't always 24 hours long, e.g. when daylight saving time changes.
+fn parse_date_end(date: &str) -> Result<i64> {
+    let date = parse_naive_date(date)?;
+    let next = date
+        .succ_opt()
+        .with_context(|| format!("No day after {date}"))?;
+    midnight(next, chrono::Local)
+}
+
+fn parse_naive_date(date: &str) -> Result<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date {date:?}, expected YYYY-MM-DD"))
+}
+
+/// The Unix timestamp of midnight at the start of `date` in `tz`
+fn midnight<Tz: chrono::TimeZone>(date: chrono::NaiveDate, tz: Tz) -> Result<i64> {
+    let midnight = date
+        .and_time(chrono::NaiveTime::MIN)
+        .and_local_timezone(tz)
+        .earliest()
+        .with_context(|| format!("No local midnight on {date}"))?;
+
+    Ok(midnight.timestamp())
+}
+
+/// How human-readable output is laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputStyle {
+    /// Decorated headings, the default. Names wider than `max_name_width` columns are truncated
+    /// to keep lines from wrapping.
+    Pretty { max_name_width: Option<usize> },
+    /// Simple labeled lines without decoration, friendlier to screen readers
+    Plain,
+}
+
+/// Room taken up by the rank before a name and the minutes and plays after it
+const RANKED_LINE_OVERHEAD: usize = 32;
+/// Narrower than this, truncated names stop being recognizable
+const MIN_NAME_WIDTH: usize = 10;
+
+/// How wide names can be to fit ranked lines into a terminal `columns` wide. Terminals too narrow
+/// for [`MIN_NAME_WIDTH`] get no truncation, letting lines wrap instead.
+fn fit_name_width(columns: usize) -> Option<usize> {
+    Some(columns.saturating_sub(RANKED_LINE_OVERHEAD)).filter(|&width| width >= MIN_NAME_WIDTH)
+}
+
+/// Shorten `name` to at most `max_width` terminal columns, ending in an ellipsis if it was cut.
+///
+/// Cuts between grapheme clusters so accented letters and emoji stay intact, and counts wide
+/// (e.g. CJK) characters as two columns.
+fn truncate_name(name: &str, max_width: Option<usize>) -> Cow<'_, str> {
+    let Some(max_width) = max_width.filter(|&max_width| name.width() > max_width) else {
+        return Cow::Borrowed(name);
     };
 
-    println!("\n=== Top Artists ({}) ===", interval_name);
-    let artists = db.get_top_artists(interval)?;
-    for (i, artist) in artists.iter().take(10).enumerate() {
-        println!(
-            "{}. {} - {} minutes ({} plays)",
+    // leave room for the ellipsis
+    let mut remaining = max_width.saturating_sub(1);
+    let mut truncated = String::new();
+    for grapheme in name.graphemes(true) {
+        let Some(left) = remaining.checked_sub(grapheme.width()) else {
+            break;
+        };
+        remaining = left;
+        truncated.push_str(grapheme);
+    }
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    Cow::Owned(truncated)
+}
+
+/// Pad `text` with spaces to `width` terminal columns
+fn pad(text: &str, width: usize) -> String {
+    format!("{text}{}", " ".repeat(width.saturating_sub(text.width())))
+}
+
+fn print_heading(style: OutputStyle, title: &str, interval_name: &str) {
+    match style {
+        OutputStyle::Pretty { .. } => println!("\n=== {} ({}) ===", title, interval_name),
+        OutputStyle::Plain => {
+            // "Top Artists" reads as "Top artists"
+            let mut chars = title.chars();
+            let title: String = chars
+                .next()
+                .into_iter()
+                .chain(chars.flat_map(char::to_lowercase))
+                .collect();
+            println!("\n{} ({}):", title, interval_name);
+        }
+    }
+}
+
+fn print_ranked(style: OutputStyle, rank: usize, name: &str, minutes: f64, plays: i64) {
+    let minutes = minutes.round() as i64;
+    match style {
+        OutputStyle::Pretty { max_name_width } => {
+            let name = truncate_name(name, max_name_width);
+            println!("{}. {} - {} minutes ({} plays)", rank, name, minutes, plays)
+        }
+        OutputStyle::Plain => println!("{}. {}, {} minutes, {} plays", rank, name, minutes, plays),
+    }
+}
+
+/// Print labeled values as horizontal bars scaled to the largest value
+fn print_bar_chart(style: OutputStyle, rows: &[(String, f64)], unit: &str) {
+    const MAX_BAR_WIDTH: f64 = 40.0;
+
+    let max_value = rows.iter().map(|(_, value)| *value).fold(0.0, f64::max);
+    let label_width = rows
+        .iter()
+        .map(|(label, _)| label.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for (label, value) in rows {
+        let rounded = value.round() as i64;
+        match style {
+            OutputStyle::Pretty { .. } => {
+                let width = if max_value > 0.0 {
+                    (value / max_value * MAX_BAR_WIDTH).round() as usize
+                } else {
+                    0
+                };
+                println!("{label:<label_width$} {} {rounded}", "█".repeat(width));
+            }
+            OutputStyle::Plain => println!("{label}, {rounded} {unit}"),
+        }
+    }
+}
+
+fn print_growth(db: &dyn PlayStore, source: Option<&str>, style: OutputStyle) -> Result<()> {
+    print_heading(style, "Distinct Artists Heard", "All Time");
+    let growth: Vec<_> = db
+        .cumulative_distinct_artists_by_month(source)?
+        .into_iter()
+        .map(|(month, count)| (month, count as f64))
+        .collect();
+    print_bar_chart(style, &growth, "artists");
+
+    Ok(())
+}
+
+fn print_release_years(
+    db: &dyn PlayStore,
+    interval: TimeInterval,
+    source: Option<&str>,
+    style: OutputStyle,
+) -> Result<()> {
+    print_heading(style, "Minutes by Release Year", interval_name(interval));
+    let years: Vec<_> = db
+        .minutes_by_release_year(interval, source)?
+        .into_iter()
+        .map(|(year, minutes)| {
+            let label = year.map_or_else(|| "Unknown".to_string(), |year| year.to_string());
+            (label, minutes)
+        })
+        .collect();
+    print_bar_chart(style, &years, "minutes");
+
+    Ok(())
+}
+
+fn print_top_days(
+    db: &dyn PlayStore,
+    interval: TimeInterval,
+    source: Option<&str>,
+    limit: Option<usize>,
+    style: OutputStyle,
+) -> Result<()> {
+    print_heading(style, "Top Listening Days", interval_name(interval));
+    let days = db.top_listening_days(interval, source, limit)?;
+    for (i, (day, minutes, plays)) in days.iter().enumerate() {
+        print_ranked(style, i + 1, day, *minutes, *plays);
+    }
+
+    Ok(())
+}
+
+fn print_absent_favorites(
+    db: &dyn PlayStore,
+    interval: TimeInterval,
+    source: Option<&str>,
+    top_n: Option<usize>,
+    style: OutputStyle,
+) -> Result<()> {
+    print_heading(style, "Favorites Not Played", interval_name(interval));
+    let artists = db.absent_favorites(interval, source, top_n)?;
+    if artists.is_empty() {
+        println!("None, you've kept up with all of them");
+    }
+    for (artist, plays) in artists {
+        match style {
+            OutputStyle::Pretty { max_name_width } => {
+                let artist = truncate_name(&artist, max_name_width);
+                println!("- {artist} ({plays} plays all time)")
+            }
+            OutputStyle::Plain => println!("{artist}, {plays} plays all time"),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_breadth(
+    db: &dyn PlayStore,
+    interval: TimeInterval,
+    source: Option<&str>,
+    limit: Option<usize>,
+    style: OutputStyle,
+) -> Result<()> {
+    print_heading(style, "Albums per Artist", interval_name(interval));
+    let artists = db.albums_per_artist(interval, source, limit)?;
+    for (i, (artist, albums, plays)) in artists.iter().enumerate() {
+        match style {
+            OutputStyle::Pretty { max_name_width } => {
+                let artist = truncate_name(artist, max_name_width);
+                println!("{}. {artist} - {albums} albums ({plays} plays)", i + 1)
+            }
+            OutputStyle::Plain => println!("{}. {artist}, {albums} albums, {plays} plays", i + 1),
+        }
+    }
+
+    Ok(())
+}
+
+/// Where `--m3u` writes a playlist to, `-` being stdout
+struct Playlist {
+    path: PathBuf,
+    music_directory: Option<PathBuf>,
+}
+
+impl Playlist {
+    fn write(&self, entries: &[playlist::Entry]) -> Result<()> {
+        let written = if self.path.as_os_str() == "-" {
+            playlist::write_m3u(
+                io::stdout().lock(),
+                entries,
+                self.music_directory.as_deref(),
+            )?
+        } else {
+            let file = fs::File::create(&self.path)
+                .with_context(|| format!("Failed to create {:?}", self.path))?;
+            let written = playlist::write_m3u(
+                io::BufWriter::new(file),
+                entries,
+                self.music_directory.as_deref(),
+            )?;
+            info!("wrote {written} songs to {:?}", self.path);
+            written
+        };
+
+        if written < entries.len() {
+            warn!(
+                "skipped {} songs without a known file (streams, or plays recorded before files were stored)",
+                entries.len() - written
+            );
+        }
+        Ok(())
+    }
+}
+
+fn print_top_songs(
+    db: &dyn PlayStore,
+    interval: TimeInterval,
+    source: Option<&str>,
+    limit: Option<usize>,
+    m3u: Option<&Playlist>,
+    style: OutputStyle,
+) -> Result<()> {
+    let songs = db.get_top_songs(interval, source, limit)?;
+
+    if let Some(m3u) = m3u {
+        return m3u.write(&songs.iter().map(playlist::Entry::from).collect::<Vec<_>>());
+    }
+
+    print_heading(style, "Top Songs", interval_name(interval));
+    for (i, song) in songs.iter().enumerate() {
+        print_ranked(
+            style,
             i + 1,
-            artist.artist_name,
-            artist.total_minutes.round() as i64,
-            artist.play_count
+            &format!("{} by {}", song.title, song.artist_name),
+            song.total_minutes,
+            song.play_count,
         );
     }
 
-    println!("\n=== Top Songs ({}) ===", interval_name);
-    let songs = db.get_top_songs(interval)?;
-    for (i, song) in songs.iter().take(10).enumerate() {
-        println!(
-            "{}. {} by {} - {} minutes ({} plays)",
+    Ok(())
+}
+
+fn print_loved(db: &dyn PlayStore, m3u: Option<&Playlist>, style: OutputStyle) -> Result<()> {
+    let tracks = db.loved_tracks()?;
+
+    if let Some(m3u) = m3u {
+        return m3u.write(&tracks.iter().map(playlist::Entry::from).collect::<Vec<_>>());
+    }
+
+    print_heading(style, "Loved Songs", "All Time");
+    if tracks.is_empty() {
+        println!("None yet, see `listener --record-reactions`");
+    }
+    for track in &tracks {
+        let title = track
+            .title
+            .as_deref()
+            .or(track.file.as_deref())
+            .unwrap_or_default();
+        let name = match &track.artist {
+            Some(artist) => Cow::Owned(format!("{title} by {artist}")),
+            None => Cow::Borrowed(title),
+        };
+        let loved_on = chrono::DateTime::from_timestamp(track.timestamp, 0)
+            .map(|at| {
+                at.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        match style {
+            OutputStyle::Pretty { max_name_width } => {
+                println!(
+                    "- {} (loved {loved_on})",
+                    truncate_name(&name, max_name_width)
+                )
+            }
+            OutputStyle::Plain => println!("{name}, loved {loved_on}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_random_sample(
+    db: &dyn PlayStore,
+    n: usize,
+    interval: TimeInterval,
+    source: Option<&str>,
+    m3u: Option<&Playlist>,
+    style: OutputStyle,
+) -> Result<()> {
+    let plays = db.random_sample(n, interval, source)?;
+
+    if let Some(m3u) = m3u {
+        return m3u.write(&plays.iter().map(playlist::Entry::from).collect::<Vec<_>>());
+    }
+
+    print_heading(style, "Random Picks", interval_name(interval));
+    for (i, play) in plays.iter().enumerate() {
+        let title = play.title.as_deref().unwrap_or_default();
+        let name = match &play.artist {
+            Some(artist) => Cow::Owned(format!("{title} by {artist}")),
+            None => Cow::Borrowed(title),
+        };
+        match style {
+            OutputStyle::Pretty { max_name_width } => {
+                println!("{}. {}", i + 1, truncate_name(&name, max_name_width))
+            }
+            OutputStyle::Plain => println!("{}. {name}", i + 1),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_loyalty(
+    db: &dyn PlayStore,
+    months: u32,
+    source: Option<&str>,
+    limit: Option<usize>,
+    style: OutputStyle,
+) -> Result<()> {
+    print_heading(
+        style,
+        "Most Loyal Artists",
+        &format!("Last {months} Months"),
+    );
+    let artists = db.artist_consistency(months, source, limit)?;
+    for (i, (artist, consistency)) in artists.iter().enumerate() {
+        let percent = (consistency * 100.0).round() as i64;
+        let active = (consistency * months as f64).round() as i64;
+        match style {
+            OutputStyle::Pretty { max_name_width } => {
+                let artist = truncate_name(artist, max_name_width);
+                println!(
+                    "{}. {artist} - {percent}% ({active} of {months} months)",
+                    i + 1
+                )
+            }
+            OutputStyle::Plain => {
+                println!("{}. {artist}, {active} of {months} months", i + 1)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print two artists' standings side by side
+fn print_comparison(style: OutputStyle, comparison: &ArtistComparison, interval_name: &str) {
+    fn date(timestamp: Option<i64>) -> String {
+        timestamp
+            .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+            .map(|time| {
+                time.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    fn rank(standing: &ArtistStanding) -> String {
+        standing
+            .rank
+            .map(|rank| format!("#{rank}"))
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    print_heading(style, "Head to Head", interval_name);
+    let sides = [&comparison.first, &comparison.second];
+
+    match style {
+        OutputStyle::Pretty { max_name_width } => {
+            let rows = [
+                (
+                    "",
+                    sides.map(|side| truncate_name(&side.artist_name, max_name_width).into_owned()),
+                ),
+                ("Rank", sides.map(rank)),
+                ("Plays", sides.map(|side| side.play_count.to_string())),
+                (
+                    "Minutes",
+                    sides.map(|side| (side.total_minutes.round() as i64).to_string()),
+                ),
+                ("First played", sides.map(|side| date(side.first_played))),
+                ("Last played", sides.map(|side| date(side.last_played))),
+            ];
+            let width = rows
+                .iter()
+                .map(|(_, [first, _])| first.width())
+                .max()
+                .unwrap_or(0);
+
+            for (label, [first, second]) in rows {
+                println!("{label:<12}  {}  {second}", pad(&first, width));
+            }
+        }
+        OutputStyle::Plain => {
+            for side in sides {
+                if side.rank.is_none() {
+                    println!("{}, not played", side.artist_name);
+                    continue;
+                }
+                println!(
+                    "{}, rank {}, {} minutes, {} plays, first played {}, last played {}",
+                    side.artist_name,
+                    side.rank.unwrap_or_default(),
+                    side.total_minutes.round() as i64,
+                    side.play_count,
+                    date(side.first_played),
+                    date(side.last_played)
+                );
+            }
+        }
+    }
+}
+
+fn print_stats(
+    db: &dyn PlayStore,
+    interval: TimeInterval,
+    source: Option<&str>,
+    split_artists: bool,
+    limit: Option<usize>,
+    style: OutputStyle,
+) -> Result<()> {
+    let interval_name = interval_name(interval);
+
+    print_heading(style, "Top Artists", interval_name);
+    let artists = if split_artists {
+        db.get_top_split_artists(interval, source, limit)?
+    } else {
+        db.get_top_artists(interval, source, limit)?
+    };
+    for (i, artist) in artists.iter().enumerate() {
+        print_ranked(
+            style,
             i + 1,
-            song.title,
-            song.artist_name,
-            song.total_minutes.round() as i64,
-            song.play_count
+            &artist.artist_name,
+            artist.total_minutes,
+            artist.play_count,
         );
     }
 
-    println!("\n=== Top Albums ({}) ===", interval_name);
-    let albums = db.get_top_albums(interval)?;
-    for (i, album) in albums.iter().take(10).enumerate() {
-        println!(
-            "{}. {} by {} - {} minutes ({} plays)",
+    print_top_songs(db, interval, source, limit, None, style)?;
+
+    print_heading(style, "Top Albums", interval_name);
+    let albums = db.get_top_albums(interval, source, limit)?;
+    for (i, album) in albums.iter().enumerate() {
+        print_ranked(
+            style,
             i + 1,
-            album.album,
-            album.artist_name,
-            album.total_minutes.round() as i64,
-            album.play_count
+            &format!("{} by {}", album.album, album.artist_name),
+            album.total_minutes,
+            album.play_count,
+        );
+    }
+
+    print_heading(style, "Top Genres", interval_name);
+    let genres = db.get_top_genres(interval, source, limit)?;
+    for (i, genre) in genres.iter().enumerate() {
+        let name = if genre.inferred {
+            format!("{} (inferred)", genre.genre)
+        } else {
+            genre.genre.clone()
+        };
+        print_ranked(style, i + 1, &name, genre.total_minutes, genre.play_count);
+    }
+
+    print_heading(style, "Summary", interval_name);
+    let novelty = db.novelty_ratio(interval, source)?;
+    println!(
+        "{}% of plays were first listens",
+        (novelty * 100.0).round() as i64
+    );
+    let (weekdays, weekends) = db.weekday_weekend_split(interval, source)?;
+    println!(
+        "Weekdays: {:.1} hours ({} plays) / Weekends: {:.1} hours ({} plays)",
+        weekdays.total_minutes / 60.0,
+        weekdays.play_count,
+        weekends.total_minutes / 60.0,
+        weekends.play_count
+    );
+    let activity = db.activity(interval, source)?;
+    if let Some((plays, minutes)) = activity.per_active_day() {
+        println!(
+            "Per active day: {plays:.1} plays, {minutes:.0} minutes ({} days with plays)",
+            activity.active_days
+        );
+    }
+    if let Some((plays, minutes)) = activity.projected_year(chrono::Utc::now()) {
+        println!(
+            "Estimate: at this rate, about {plays} plays ({:.0} hours) over a full year",
+            minutes / 60.0
+        );
+    }
+    let (random, in_order) = db.minutes_by_random_mode(interval, source)?;
+    if random + in_order > 0.0 {
+        println!(
+            "Shuffled: {:.1} hours / In order: {:.1} hours",
+            random / 60.0,
+            in_order / 60.0
         );
     }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+fn parse_connect_timeout(pargs: &mut pico_args::Arguments) -> Result<std::time::Duration> {
+    match pargs.opt_value_from_str("--connect-timeout")? {
+        Some(0) => bail!("--connect-timeout must be at least 1 second"),
+        Some(seconds) => Ok(std::time::Duration::from_secs(seconds)),
+        None => Ok(mpd::DEFAULT_CONNECT_TIMEOUT),
+    }
+}
+
+/// How log lines are rendered
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum LogFormat {
+    /// For people reading the terminal or journal
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
 
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("expected text or json, not {s:?}")),
+        }
+    }
+}
+
+/// Log in `format`, at the levels set in `RUST_LOG` (info and up by default)
+fn init_logging(format: LogFormat) {
+    let filter = tracing_subscriber::EnvFilter::builder()
+        .with_default_directive(tracing::level_filters::LevelFilter::INFO.into())
+        .from_env_lossy();
+    // stdout is kept for output, e.g. CSV exports and playlists
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+fn main() -> Result<()> {
     let mut pargs = pico_args::Arguments::from_env();
 
     // Check for subcommands
     let subcommand: Option<String> = pargs.subcommand()?;
 
+    // the flag takes precedence over the environment
+    let log_format = match pargs.opt_value_from_str("--log-format")? {
+        Some(format) => format,
+        None => match std::env::var("LOG_FORMAT") {
+            Ok(format) => format
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid LOG_FORMAT: {e}"))?,
+            Err(_) => LogFormat::default(),
+        },
+    };
+    init_logging(log_format);
+    let config_path = match pargs.opt_value_from_str::<_, PathBuf>("--config")? {
+        Some(path) if !path.exists() => bail!("Config file {path:?} does not exist"),
+        Some(path) => path,
+        None => get_config_path()?,
+    };
+
     let db_path = get_db_path()?;
+
+    // diagnoses problems with the config and database, so must not fail on them itself
+    if subcommand.as_deref() == Some("doctor") {
+        let mpd_address = pargs
+            .opt_value_from_str("--mpd")?
+            .unwrap_or_else(|| "127.0.0.1:6600".to_string());
+        let connect_timeout = parse_connect_timeout(&mut pargs)?;
+        return doctor::run(&config_path, &db_path, &mpd_address, connect_timeout);
+    }
+
     if db_path.exists() {
         info!("found existing db at {db_path:?}");
     } else {
         info!("no existing database found, creating one at {db_path:?}");
     }
-    let db = MusicDb::new(db_path.as_path())?;
+    let config = Config::load(&config_path)?;
+
+    // refreshing the query for as long as the listener runs, keep out of its way
+    let watch = subcommand.as_deref() == Some("query") && pargs.contains("--watch");
+    let mut db = MusicDb::new(db_path.as_path())?;
+    if watch {
+        // opened normally first to create and migrate the database
+        db = MusicDb::open_read_only(db_path.as_path())?;
+    }
+    if config.artists.split {
+        db = db.with_artist_separators(config.artists.separators.clone());
+    }
+    if let Some(timezone) = config.timezone {
+        db = db.with_timezone(timezone)?;
+    }
+    let db: Box<dyn PlayStore> = Box::new(db);
 
     match subcommand.as_deref() {
         Some("query") => {
-            let interval = if pargs.contains("--week") {
-                TimeInterval::Week
-            } else if pargs.contains("--month") {
-                TimeInterval::Month
-            } else if pargs.contains("--year") {
-                TimeInterval::Year
-            } else if pargs.contains("--all") {
-                TimeInterval::AllTime
+            let now = chrono::Utc::now().timestamp();
+
+            let interval = if pargs.contains("--since-last-query") {
+                match db.last_query_timestamp()? {
+                    Some(timestamp) => TimeInterval::Since(timestamp),
+                    None => {
+                        println!("No previous query recorded, showing the last 24 hours");
+                        TimeInterval::Since(now - 24 * 60 * 60)
+                    }
+                }
+            } else {
+                parse_interval(&mut pargs)
+            };
+
+            let no_truncate = pargs.contains("--no-truncate");
+            let max_name_width: Option<usize> = pargs.opt_value_from_str("--max-name-width")?;
+            if let Some(width) = max_name_width.filter(|&width| width < MIN_NAME_WIDTH) {
+                bail!("--max-name-width must be at least {MIN_NAME_WIDTH}, not {width}");
+            }
+            let style = if pargs.contains("--plain") {
+                OutputStyle::Plain
+            } else if no_truncate {
+                OutputStyle::Pretty {
+                    max_name_width: None,
+                }
             } else {
-                // Default to all time if no flag specified
-                TimeInterval::AllTime
+                // fit the terminal, unless the output goes elsewhere
+                let max_name_width = max_name_width.or_else(|| {
+                    terminal_size::terminal_size()
+                        .and_then(|(width, _)| fit_name_width(width.0 as usize))
+                });
+                OutputStyle::Pretty { max_name_width }
             };
 
-            print_stats(&db, interval)?;
+            let source: Option<String> = pargs.opt_value_from_str("--source")?;
+            let growth = pargs.contains("--growth");
+            let by_year = pargs.contains("--by-year");
+            let top_days = pargs.contains("--top-days");
+            let loyalty = pargs.contains("--loyalty");
+            let absent = pargs.contains("--absent");
+            let breadth = pargs.contains("--breadth");
+            let months = match pargs.opt_value_from_str("--months")? {
+                Some(0) => bail!("--months must be at least 1"),
+                Some(months) => months,
+                None => 12,
+            };
+            let split_artists = pargs.contains("--split-artists");
+            // 0 lists everything rather than nothing
+            let limit = match pargs.opt_value_from_str("--limit")? {
+                Some(0) => None,
+                Some(limit) => Some(limit),
+                None => Some(10),
+            };
+            let refresh = match pargs.opt_value_from_str("--refresh")? {
+                Some(0) => bail!("--refresh must be at least 1 second"),
+                Some(seconds) => std::time::Duration::from_secs(seconds),
+                None => std::time::Duration::from_secs(60),
+            };
+            let random_sample: Option<usize> = pargs.opt_value_from_str("--random")?;
+            let top_songs = pargs.contains("--songs");
+            let loved = pargs.contains("--loved");
+            let m3u = pargs
+                .opt_value_from_str("--m3u")?
+                .map(|path: PathBuf| -> Result<_> {
+                    Ok(Playlist {
+                        path,
+                        music_directory: pargs.opt_value_from_str("--music-dir")?,
+                    })
+                })
+                .transpose()?;
+            if m3u.is_some() && !top_songs && !loved && random_sample.is_none() {
+                bail!("--m3u needs --songs, --loved or --random");
+            }
+            let svg: Option<PathBuf> = pargs.opt_value_from_str("--svg")?;
+            #[cfg(feature = "svg")]
+            let chart_stat: chart::ChartStat =
+                pargs.opt_value_from_str("--chart")?.unwrap_or_default();
+            #[cfg(not(feature = "svg"))]
+            if svg.is_some() {
+                bail!("--svg needs mpd-wrapped built with the `svg` feature");
+            }
+            let compare: Option<String> = pargs.opt_value_from_str("--compare-artists")?;
+            let compare = match compare {
+                Some(first) => {
+                    let second: String = pargs
+                        .free_from_str()
+                        .context("--compare-artists takes two artist names")?;
+                    Some((first, second))
+                }
+                None => None,
+            };
+
+            let show = || -> Result<()> {
+                #[cfg(feature = "svg")]
+                if let Some(path) = &svg {
+                    let rendered = chart::render(
+                        db.as_ref(),
+                        chart_stat,
+                        interval,
+                        interval_name(interval),
+                        source.as_deref(),
+                        split_artists,
+                        limit,
+                    )?;
+                    fs::write(path, rendered)
+                        .with_context(|| format!("Failed to write chart to {path:?}"))?;
+                    return Ok(());
+                }
+
+                if let Some((first, second)) = &compare {
+                    let comparison =
+                        db.compare_artists(first, second, interval, source.as_deref())?;
+                    print_comparison(style, &comparison, interval_name(interval));
+                } else if growth {
+                    print_growth(db.as_ref(), source.as_deref(), style)?;
+                } else if by_year {
+                    print_release_years(db.as_ref(), interval, source.as_deref(), style)?;
+                } else if top_days {
+                    print_top_days(db.as_ref(), interval, source.as_deref(), limit, style)?;
+                } else if let Some(n) = random_sample {
+                    print_random_sample(
+                        db.as_ref(),
+                        n,
+                        interval,
+                        source.as_deref(),
+                        m3u.as_ref(),
+                        style,
+                    )?;
+                } else if loved {
+                    print_loved(db.as_ref(), m3u.as_ref(), style)?;
+                } else if top_songs {
+                    print_top_songs(
+                        db.as_ref(),
+                        interval,
+                        source.as_deref(),
+                        limit,
+                        m3u.as_ref(),
+                        style,
+                    )?;
+                } else if breadth {
+                    print_breadth(db.as_ref(), interval, source.as_deref(), limit, style)?;
+                } else if absent {
+                    print_absent_favorites(db.as_ref(), interval, source.as_deref(), limit, style)?;
+                } else if loyalty {
+                    print_loyalty(db.as_ref(), months, source.as_deref(), limit, style)?;
+                } else {
+                    print_stats(
+                        db.as_ref(),
+                        interval,
+                        source.as_deref(),
+                        split_artists,
+                        limit,
+                        style,
+                    )?;
+                }
+                Ok(())
+            };
+
+            if watch {
+                let (interrupted, interrupt) = mpsc::channel();
+                ctrlc::set_handler(move || {
+                    let _ = interrupted.send(());
+                })?;
+
+                loop {
+                    // clear the screen and move the cursor to the top left
+                    print!("\x1b[2J\x1b[H");
+                    show()?;
+                    if interrupt.recv_timeout(refresh) != Err(RecvTimeoutError::Timeout) {
+                        break;
+                    }
+                }
+            } else {
+                show()?;
+                db.set_last_query_timestamp(now)?;
+            }
+        }
+        Some("export") => {
+            let interval = parse_interval(&mut pargs);
+            let from: Option<String> = pargs.opt_value_from_str("--from")?;
+            let to: Option<String> = pargs.opt_value_from_str("--to")?;
+            let output: Option<PathBuf> = pargs.opt_value_from_str("--output")?;
+            let precision = pargs
+                .opt_value_from_str("--round")?
+                .unwrap_or(config.export.timestamp_precision);
+
+            // explicit bounds take precedence over the interval, --to includes the whole day
+            let start = match &from {
+                Some(date) => Some(parse_date(date)?),
+                None => interval.cutoff_timestamp(),
+            };
+            let end = match &to {
+                Some(date) => Some(parse_date_end(date)?),
+                None => None,
+            };
+            let mut description = match (&from, &to) {
+                (None, None) => interval_name(interval).to_string(),
+                (from, to) => format!(
+                    "{} to {}",
+                    from.as_deref().unwrap_or(interval_name(interval)),
+                    to.as_deref().unwrap_or("now")
+                ),
+            };
+            match precision {
+                export::TimestampPrecision::Second => {}
+                export::TimestampPrecision::Minute => {
+                    description.push_str(", timestamps rounded down to the minute")
+                }
+                export::TimestampPrecision::Hour => {
+                    description.push_str(", timestamps rounded down to the hour")
+                }
+            }
+
+            let out: Box<dyn io::Write> = match &output {
+                Some(path) => {
+                    let file = fs::File::create(path)
+                        .with_context(|| format!("Failed to create {path:?}"))?;
+                    Box::new(io::BufWriter::new(file))
+                }
+                None => Box::new(io::stdout().lock()),
+            };
+            let mut csv = export::CsvWriter::new(out, &description)?.with_precision(precision);
+            db.for_each_play(start, end, &mut |play| Ok(csv.write(&play)?))?;
+            let count = csv.finish()?;
+            if let Some(path) = output {
+                info!("exported {count} plays to {path:?}");
+            }
+        }
+        Some("reprocess") => {
+            if pargs.contains("--dry-run") {
+                let changed = db.reprocess_plays(&config.tags, true)?;
+                println!("Would update {changed} plays");
+            } else {
+                let changed = db.reprocess_plays(&config.tags, false)?;
+                println!("Updated {changed} plays");
+            }
+        }
+        Some("sources") => {
+            for (source, play_count) in db.sources()? {
+                match source {
+                    Some(source) => println!("{source} ({play_count} plays)"),
+                    None => println!("(no source) ({play_count} plays)"),
+                }
+            }
+        }
+        Some("enrich") => {
+            if !pargs.contains("--genres") {
+                bail!("Nothing to enrich, pass --genres to look up missing genres on MusicBrainz");
+            }
+
+            let mut source = enrich::MusicBrainz::new();
+            let updated = enrich::enrich_genres(db.as_ref(), &mut source)?;
+            println!("Derived genres for {updated} plays");
         }
         Some("listener") => {
             let mpd_address = pargs
                 .opt_value_from_str("--mpd")?
                 .unwrap_or_else(|| "127.0.0.1:6600".to_string());
+            let source: Option<String> = pargs.opt_value_from_str("--source")?;
+
+            let _lock = acquire_listener_lock(source.as_deref(), pargs.contains("--force"))?;
+
+            let buffer_config = BufferConfig::with_limits(
+                pargs.opt_value_from_str("--buffer-size")?,
+                pargs
+                    .opt_value_from_str("--buffer-secs")?
+                    .map(std::time::Duration::from_secs),
+            );
+            let heartbeat = match pargs.opt_value_from_str("--heartbeat")? {
+                Some(0) => bail!("--heartbeat must be at least 1 second"),
+                Some(seconds) => std::time::Duration::from_secs(seconds),
+                None => mpd::DEFAULT_HEARTBEAT,
+            };
+            let debug_record = pargs.contains("--debug-record");
+            let connect_timeout = parse_connect_timeout(&mut pargs)?;
+            let record_queue = pargs.contains("--record-queue");
+            let record_reactions = pargs.contains("--record-reactions");
+            let catch_up = pargs.contains("--catch-up");
+
+            if catch_up {
+                let (client, _) = mpd::connect_client(&mpd_address, connect_timeout)?;
+                let mut playcounts = catch_up::StickerPlaycounts {
+                    client,
+                    sticker: config.listener.playcount_sticker.clone(),
+                };
+                let backfilled = catch_up::catch_up(
+                    db.as_ref(),
+                    &mut playcounts,
+                    &config.tags,
+                    source.as_deref(),
+                    chrono::Utc::now(),
+                )?;
+                info!("Backfilled {backfilled} plays missed since the last catch-up");
+            }
 
             info!("Connecting to MPD...");
-            let status_iter = mpd::StatusIterator::new(mpd_address)?;
-            let listen_iter = mpd::ListenIterator::new(status_iter);
+            let mut status_iter =
+                mpd::StatusIterator::new(&mpd_address, heartbeat, connect_timeout)?;
+            if record_queue {
+                status_iter = status_iter.with_queue_tracking();
+            }
+            if record_reactions {
+                status_iter = status_iter.with_sticker_tracking(&config.reactions.sticker);
+            }
+            let server_version = mpd::format_version(status_iter.server_version());
+            info!("Connected to MPD {server_version}");
+            let session = db.start_session(
+                source.as_deref(),
+                Some(&server_version),
+                chrono::Utc::now().timestamp(),
+            )?;
+
+            // Ctrl-C and SIGTERM end the listener at the next status, at most a heartbeat later,
+            // so buffered plays are committed rather than lost
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopping = Arc::clone(&stop);
+            ctrlc::set_handler(move || stopping.store(true, Ordering::Relaxed))?;
+
+            let writer = RefCell::new(db.begin_buffered(buffer_config));
+            let status_iter = status_iter.inspect(|status| {
+                let now = chrono::Utc::now().timestamp();
+                if let Err(e) = db.record_heartbeat(session, now) {
+                    warn!("Failed to record heartbeat: {e:#}");
+                }
+                // --buffer-secs applies between plays too
+                if let Err(e) = writer.borrow_mut().flush_if_due() {
+                    warn!("Failed to commit buffered plays: {e:#}");
+                }
+                if debug_record {
+                    if let Err(e) = db.record_status_snapshot(session, now, status) {
+                        warn!("Failed to record status snapshot: {e:#}");
+                    }
+                }
+                for queued in &status.queued {
+                    let mut record = QueuedRecord::from(queued.clone());
+                    record.source = source.clone();
+                    if let Err(e) = db.log_queued(&record) {
+                        warn!("Failed to record queued song {:?}: {e:#}", record.file);
+                    }
+                }
+                for change in &status.sticker_changes {
+                    let reaction = config.reactions.reaction(change.value.as_deref());
+                    let mut record = ReactionRecord::new(change.clone(), reaction);
+                    record.source = source.clone();
+                    if let Err(e) = db.record_reaction(&record) {
+                        warn!("Failed to record reaction to {:?}: {e:#}", record.file);
+                    }
+                }
+            });
+            let listen_config = mpd::ListenConfig {
+                require_start: config.listener.require_start,
+                threshold_mode: config.listener.threshold_mode,
+                min_track_length: std::time::Duration::from_secs(
+                    config.listener.min_track_length_seconds,
+                ),
+                restart_threshold: std::time::Duration::from_secs(
+                    config.listener.restart_threshold_seconds,
+                ),
+                restart_window: std::time::Duration::from_secs(
+                    config.listener.restart_window_seconds,
+                ),
+                ..Default::default()
+            };
+            let status_iter = status_iter.take_while(|_| !stop.load(Ordering::Relaxed));
+            let listen_iter = mpd::ListenIterator::with_config(status_iter, listen_config);
 
             for listen in listen_iter {
-                db.log_play(&listen.into())?;
+                let mut record = PlayRecord::from_listen(listen, &config.tags);
+                record.source = source.clone();
+                writer.borrow_mut().push(record)?;
+            }
+            writer.borrow_mut().flush()?;
+            if stop.load(Ordering::Relaxed) {
+                info!("Stopped listening")
+            } else {
+                info!("Disconnected from MPD")
             }
-            info!("Disconnected from MPD")
         }
         _ => {
             eprintln!("Usage:");
-            eprintln!("  mpd-wrapped listener [--mpd <address>]  # Run listener mode");
-            eprintln!("  mpd-wrapped query [--week|--month|--year|--all]  # Query statistics");
+            eprintln!("  mpd-wrapped listener [--mpd <address>] [--source <name>] [--buffer-size <n>] [--buffer-secs <t>] [--heartbeat <t>] [--connect-timeout <t>] [--record-queue] [--record-reactions] [--catch-up] [--debug-record] [--force]  # Run listener mode");
+            eprintln!("  mpd-wrapped query [--week|--month|--year|--all|--since-last-query] [--source <name>] [--growth] [--by-year] [--top-days] [--loyalty [--months <n>]] [--absent] [--breadth] [--songs] [--loved] [--random <n>] [--m3u <file> [--music-dir <dir>]] [--compare-artists <a> <b>] [--split-artists] [--limit <n>] [--max-name-width <n>|--no-truncate] [--plain] [--svg <file> [--chart artists|songs|albums]] [--watch [--refresh <t>]]  # Query statistics");
+            eprintln!("  mpd-wrapped sources  # List sources plays were recorded from");
+            eprintln!("  mpd-wrapped reprocess [--dry-run]  # Apply the current [tags] and [artists] settings to past plays, deleting dropped tags for good");
+            eprintln!("  mpd-wrapped doctor [--mpd <address>] [--connect-timeout <t>]  # Check the setup for problems");
+            eprintln!("  mpd-wrapped export [--week|--month|--year|--all] [--from <date>] [--to <date>] [--round minute|hour] [--output <file>]  # Export plays as CSV");
+            eprintln!(
+                "  mpd-wrapped enrich --genres  # Look up genres missing from tags on MusicBrainz"
+            );
+            eprintln!("\nOptions:");
+            eprintln!("  --config <file>  # Read settings from this .toml or .json file");
+            eprintln!("  --log-format text|json  # How to render log lines, text by default (or set LOG_FORMAT)");
             eprintln!("\nExamples:");
             eprintln!("  mpd-wrapped query --week");
             eprintln!("  mpd-wrapped query --all");
+            eprintln!("  mpd-wrapped query --since-last-query");
+            eprintln!("  mpd-wrapped query --year --compare-artists Vulfpeck Khruangbin");
+            eprintln!("  mpd-wrapped export --from 2024-01-01 --to 2024-12-31 --output 2024.csv");
             eprintln!("  mpd-wrapped listener --mpd 127.0.0.1:6600");
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_midnight() -> Result<()> {
+        let berlin = chrono_tz::Europe::Berlin;
+        let day = |date| parse_naive_date(date).and_then(|date| midnight(date, berlin));
+
+        assert_eq!(day("2024-03-01")?, 1709247600);
+        // daylight saving time starts on the 31st, which is only 23 hours long
+        assert_eq!(day("2024-04-01")? - day("2024-03-31")?, 23 * 60 * 60);
+        // and ends on October 27th, which is 25
+        assert_eq!(day("2024-10-28")? - day("2024-10-27")?, 25 * 60 * 60);
+        assert!(parse_naive_date("2024-02-30").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_name() {
+        assert_eq!(truncate_name("Radiohead", Some(20)), "Radiohead");
+        assert_eq!(truncate_name("Radiohead", None), "Radiohead");
+        assert_eq!(truncate_name("Radiohead", Some(6)), "Radio…");
+
+        // combining accents stay attached to their letter
+        assert_eq!(
+            truncate_name("Beyonce\u{301} feat. Jay-Z", Some(8)),
+            "Beyonce\u{301}…"
+        );
+
+        // wide characters take two columns each and aren't split
+        let name = "坂本龍一 - 戦場のメリークリスマス";
+        let truncated = truncate_name(name, Some(10));
+        assert_eq!(truncated, "坂本龍一…");
+        assert!(truncated.width() <= 10);
+        assert_eq!(truncate_name("🎸🎸🎸", Some(4)), "🎸…");
+    }
+
+    #[test]
+    fn test_fit_name_width() {
+        assert_eq!(fit_name_width(80), Some(80 - RANKED_LINE_OVERHEAD));
+        assert_eq!(
+            fit_name_width(RANKED_LINE_OVERHEAD + MIN_NAME_WIDTH),
+            Some(MIN_NAME_WIDTH)
+        );
+        assert_eq!(fit_name_width(RANKED_LINE_OVERHEAD + 1), None);
+        assert_eq!(fit_name_width(20), None);
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_pad() {
+        assert_eq!(pad("坂本", 6), "坂本  ");
+        assert_eq!(pad("Radiohead", 4), "Radiohead");
+    }
+}
@@ -1,11 +1,67 @@
 use crate::mpd::status_iterator::SongStatus;
-use mpd::Song;
+use mpd::{Song, State};
+use serde::Deserialize;
 use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct SongListenRecord {
     pub song: Song,
     pub start: chrono::DateTime<chrono::Utc>,
+    /// Whether random mode was on when the listen started
+    pub random: bool,
+    /// The song's duration as MPD reported it during the listen, `None` if it reported none (e.g.
+    /// for streams). Takes precedence over the song's own duration, which may be missing.
+    pub duration: Option<Duration>,
+}
+
+/// How the time and fraction thresholds of a [`ListenConfig`] combine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThresholdMode {
+    /// A listen counts once either threshold is met
+    #[default]
+    Any,
+    /// A listen only counts once both thresholds are met.
+    ///
+    /// Songs shorter than `min_listen_time` never count. Long songs have to be played for
+    /// `min_listen_fraction` of their length rather than just `min_listen_time`. Songs of unknown
+    /// length, like streams, never count.
+    All,
+}
+
+/// Thresholds controlling when a listen is counted and when it is considered restarted
+#[derive(Debug, Clone)]
+pub struct ListenConfig {
+    /// A listen counts once this much of the song has been played
+    pub min_listen_time: Duration,
+    /// ... or once this fraction of the song has been played
+    pub min_listen_fraction: f64,
+    /// Whether either of the two thresholds above is enough, or both are needed
+    pub threshold_mode: ThresholdMode,
+    /// How far into the song playback must have progressed before jumping back counts as a restart
+    pub restart_threshold: Duration,
+    /// How close to the start the new position must be for a jump back to count as a restart
+    pub restart_window: Duration,
+    /// Only count listens observed from within `restart_window` of the start, not ones already in
+    /// progress when first seen (e.g. when the listener starts mid-song)
+    pub require_start: bool,
+    /// Songs shorter than this aren't tracked at all, e.g. interludes and sound effects. Streams
+    /// and songs of unknown length report no duration and are always tracked.
+    pub min_track_length: Duration,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            min_listen_time: Duration::from_secs(20),
+            min_listen_fraction: 0.6,
+            threshold_mode: ThresholdMode::Any,
+            restart_threshold: Duration::from_secs(5),
+            restart_window: Duration::from_secs(5),
+            require_start: false,
+            min_track_length: Duration::ZERO,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -13,10 +69,27 @@ pub struct CurrentListen {
     song: Song,
     start: chrono::DateTime<chrono::Utc>,
     max_elapsed: Duration,
+    /// The song's duration as reported when the listen started
+    duration: Duration,
+    random: bool,
+    /// Whether the song was first seen near its beginning
+    from_start: bool,
+}
+
+impl CurrentListen {
+    fn into_record(self) -> SongListenRecord {
+        SongListenRecord {
+            song: self.song,
+            start: self.start,
+            random: self.random,
+            duration: (!self.duration.is_zero()).then_some(self.duration),
+        }
+    }
 }
 
 pub struct ListenIterator<I> {
     inner: I,
+    config: ListenConfig,
     current_listen: Option<CurrentListen>,
 }
 
@@ -24,27 +97,49 @@ impl<I> ListenIterator<I>
 where
     I: Iterator<Item = SongStatus>,
 {
-    pub fn new(inner: I) -> Self {
+    pub fn with_config(inner: I, config: ListenConfig) -> Self {
         Self {
             inner,
+            config,
             current_listen: None,
         }
     }
 
-    fn should_emit(max_elapsed: Duration, total_duration: Duration) -> bool {
-        let threshold_time = Duration::from_secs(20);
-        let threshold_percentage = 0.6;
+    fn start_listen(&self, status: SongStatus) -> CurrentListen {
+        CurrentListen {
+            song: status.song,
+            start: chrono::Utc::now(),
+            max_elapsed: status.elapsed,
+            duration: status.duration,
+            random: status.random,
+            from_start: status.elapsed < self.config.restart_window,
+        }
+    }
+
+    /// Whether `listen` was long enough to count, measured against the duration of its own song
+    fn should_emit(&self, listen: &CurrentListen) -> bool {
+        if self.config.require_start && !listen.from_start {
+            return false;
+        }
 
-        let time_threshold_met = max_elapsed >= threshold_time;
-        let percentage_threshold_met = total_duration.as_secs() > 0
-            && max_elapsed.as_secs_f64() / total_duration.as_secs_f64() >= threshold_percentage;
+        let max_elapsed = listen.max_elapsed;
+        let time_threshold_met = max_elapsed >= self.config.min_listen_time;
+        let percentage_threshold_met = listen.duration.as_secs() > 0
+            && max_elapsed.as_secs_f64() / listen.duration.as_secs_f64()
+                >= self.config.min_listen_fraction;
 
-        time_threshold_met || percentage_threshold_met
+        match self.config.threshold_mode {
+            ThresholdMode::Any => time_threshold_met || percentage_threshold_met,
+            ThresholdMode::All => time_threshold_met && percentage_threshold_met,
+        }
     }
 
-    fn is_restart(elapsed: Duration, max_elapsed: Duration) -> bool {
-        let restart_threshold = Duration::from_secs(5);
-        elapsed < restart_threshold && max_elapsed >= restart_threshold
+    /// A restart is a jump back to (near) the beginning after having made some progress.
+    /// Seeking backwards to anywhere else in the song is just a seek.
+    fn is_restart(&self, elapsed: Duration, max_elapsed: Duration) -> bool {
+        elapsed < max_elapsed
+            && elapsed < self.config.restart_window
+            && max_elapsed >= self.config.restart_threshold
     }
 }
 
@@ -58,51 +153,49 @@ where
         loop {
             let status = self.inner.next()?;
 
+            if status.state == State::Stop {
+                // Playback ended (e.g. the queue ran out), no song change will follow
+                if let Some(listen) = self.current_listen.take() {
+                    if self.should_emit(&listen) {
+                        return Some(listen.into_record());
+                    }
+                }
+                continue;
+            }
+
+            // Songs too short to be tracked still end the previous listen, streams (with no
+            // duration) aren't too short
+            let eligible =
+                status.duration.is_zero() || status.duration >= self.config.min_track_length;
+
             match self.current_listen.take() {
+                None if !eligible => {}
                 None => {
                     // First song
-                    self.current_listen = Some(CurrentListen {
-                        song: status.song,
-                        start: chrono::Utc::now(),
-                        max_elapsed: status.elapsed,
-                    });
+                    self.current_listen = Some(self.start_listen(status));
                 }
                 Some(listen) if listen.song.file != status.song.file => {
                     // Different song - check if we should emit the previous listen
-                    let should_emit = Self::should_emit(listen.max_elapsed, status.duration);
+                    let should_emit = self.should_emit(&listen);
 
                     // Start tracking new song
-                    self.current_listen = Some(CurrentListen {
-                        song: status.song,
-                        start: chrono::Utc::now(),
-                        max_elapsed: status.elapsed,
-                    });
+                    self.current_listen = eligible.then(|| self.start_listen(status));
 
                     if should_emit {
-                        return Some(SongListenRecord {
-                            song: listen.song,
-                            start: listen.start,
-                        });
+                        return Some(listen.into_record());
                     }
                 }
                 Some(mut listen) => {
                     // Same song
-                    if Self::is_restart(status.elapsed, listen.max_elapsed) {
+                    if self.is_restart(status.elapsed, listen.max_elapsed) {
                         // Jumped back to start - emit if threshold met
-                        let should_emit = Self::should_emit(listen.max_elapsed, status.duration);
+                        let should_emit = self.should_emit(&listen);
 
                         // Start new listen of same song
-                        self.current_listen = Some(CurrentListen {
-                            song: listen.song.clone(),
-                            start: chrono::Utc::now(),
-                            max_elapsed: status.elapsed,
-                        });
+                        self.current_listen = Some(self.start_listen(status));
 
                         if should_emit {
-                            return Some(SongListenRecord {
-                                song: listen.song,
-                                start: listen.start,
-                            });
+                            return Some(listen.into_record());
                         }
                     } else {
                         // Update max_elapsed if progressing forward
@@ -116,3 +209,261 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(file: &str, elapsed: u64, duration: u64) -> SongStatus {
+        SongStatus {
+            state: State::Play,
+            random: false,
+            song: Song {
+                file: file.to_string(),
+                ..Default::default()
+            },
+            duration: Duration::from_secs(duration),
+            elapsed: Duration::from_secs(elapsed),
+            queued: Vec::new(),
+            sticker_changes: Vec::new(),
+        }
+    }
+
+    fn stopped(file: &str) -> SongStatus {
+        SongStatus {
+            state: State::Stop,
+            duration: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            ..status(file, 0, 0)
+        }
+    }
+
+    fn listens(statuses: Vec<SongStatus>, config: ListenConfig) -> Vec<SongListenRecord> {
+        ListenIterator::with_config(statuses.into_iter(), config).collect()
+    }
+
+    #[test]
+    fn test_restart_to_beginning_emits() {
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 150, 200),
+            status("a.flac", 1, 200),
+        ];
+
+        assert_eq!(listens(statuses, ListenConfig::default()).len(), 1);
+    }
+
+    #[test]
+    fn test_backward_seek_mid_track_is_not_restart() {
+        // seek forward, then back into the middle of the song
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 150, 200),
+            status("a.flac", 60, 200),
+            status("a.flac", 90, 200),
+        ];
+
+        assert!(listens(statuses, ListenConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn test_backward_seek_below_restart_threshold_is_not_restart() {
+        let config = ListenConfig {
+            restart_threshold: Duration::from_secs(30),
+            ..Default::default()
+        };
+
+        // jumping back to 20s is still a seek even though it is below the restart threshold
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 150, 200),
+            status("a.flac", 20, 200),
+        ];
+
+        assert!(listens(statuses, config).is_empty());
+    }
+
+    #[test]
+    fn test_heartbeats_refresh_progress_without_emitting() {
+        // a player event at the start of the song, then heartbeats while it plays
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 5, 200),
+            status("a.flac", 10, 200),
+            status("a.flac", 25, 200),
+        ];
+        assert!(listens(statuses.clone(), ListenConfig::default()).is_empty());
+
+        let mut statuses = statuses;
+        statuses.push(status("b.flac", 0, 200));
+        assert_eq!(listens(statuses, ListenConfig::default()).len(), 1);
+    }
+
+    #[test]
+    fn test_song_in_progress_at_start() {
+        // the listener started with the song already 150s in
+        let statuses = vec![
+            status("a.flac", 150, 200),
+            status("a.flac", 190, 200),
+            status("b.flac", 0, 200),
+            status("b.flac", 150, 200),
+            status("c.flac", 0, 200),
+        ];
+        assert_eq!(listens(statuses.clone(), ListenConfig::default()).len(), 2);
+
+        let config = ListenConfig {
+            require_start: true,
+            ..Default::default()
+        };
+        let emitted = listens(statuses, config);
+        assert_eq!(emitted.len(), 1);
+        assert_eq!(emitted[0].song.file, "b.flac");
+    }
+
+    #[test]
+    fn test_short_tracks_are_not_tracked() {
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 150, 200),
+            status("interlude.flac", 0, 10),
+            status("interlude.flac", 9, 10),
+            status("b.flac", 0, 200),
+        ];
+        let config = ListenConfig {
+            min_listen_time: Duration::from_secs(5),
+            ..Default::default()
+        };
+        assert_eq!(listens(statuses.clone(), config.clone()).len(), 2);
+
+        let config = ListenConfig {
+            min_track_length: Duration::from_secs(30),
+            ..config
+        };
+        let emitted = listens(statuses, config);
+        assert_eq!(emitted.len(), 1);
+        assert_eq!(emitted[0].song.file, "a.flac");
+    }
+
+    #[test]
+    fn test_streams_are_not_short_tracks() {
+        // radio reports no duration
+        let statuses = vec![
+            status("http://radio.example/stream", 0, 0),
+            status("http://radio.example/stream", 150, 0),
+            status("b.flac", 0, 200),
+        ];
+        let config = ListenConfig {
+            min_track_length: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let emitted = listens(statuses, config);
+        assert_eq!(emitted.len(), 1);
+        assert_eq!(emitted[0].song.file, "http://radio.example/stream");
+    }
+
+    #[test]
+    fn test_queue_end_emits_final_song() {
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 150, 200),
+            status("b.flac", 0, 200),
+            status("b.flac", 199, 200),
+            stopped("b.flac"),
+        ];
+        let emitted = listens(statuses, ListenConfig::default());
+        assert_eq!(emitted.len(), 2);
+        assert_eq!(emitted[1].song.file, "b.flac");
+
+        // stopping right after a song started doesn't count it
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 3, 200),
+            stopped("a.flac"),
+        ];
+        assert!(listens(statuses, ListenConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn test_observed_duration_carried_through() {
+        // the song's own duration disagrees with the one MPD reports while it plays
+        let mut first = status("a.flac", 0, 200);
+        first.song.duration = Some(Duration::from_secs(180));
+        let statuses = vec![
+            first.clone(),
+            SongStatus {
+                elapsed: Duration::from_secs(150),
+                ..first
+            },
+            status("b.flac", 0, 0),
+            status("b.flac", 60, 0),
+            status("c.flac", 0, 200),
+        ];
+
+        let emitted = listens(statuses, ListenConfig::default());
+        assert_eq!(emitted.len(), 2);
+        assert_eq!(emitted[0].duration, Some(Duration::from_secs(200)));
+        // streams report no duration
+        assert_eq!(emitted[1].duration, None);
+    }
+
+    #[test]
+    fn test_listen_measured_against_its_own_song() {
+        // 15s of a 20s song counts, even though the next song is much longer
+        let statuses = vec![
+            status("a.flac", 0, 20),
+            status("a.flac", 15, 20),
+            status("b.flac", 0, 300),
+        ];
+        assert_eq!(listens(statuses, ListenConfig::default()).len(), 1);
+
+        // and 10s of a 300s song doesn't, even though the next song is short
+        let statuses = vec![
+            status("a.flac", 0, 300),
+            status("a.flac", 10, 300),
+            status("b.flac", 0, 12),
+        ];
+        assert!(listens(statuses, ListenConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn test_threshold_mode() {
+        // 60s into a 200s song meets the time threshold but not the fraction
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 60, 200),
+            status("b.flac", 0, 200),
+        ];
+        assert_eq!(listens(statuses.clone(), ListenConfig::default()).len(), 1);
+
+        let strict = ListenConfig {
+            threshold_mode: ThresholdMode::All,
+            ..Default::default()
+        };
+        assert!(listens(statuses, strict.clone()).is_empty());
+
+        // 130s of the same song meets both
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 130, 200),
+            status("b.flac", 0, 200),
+        ];
+        assert_eq!(listens(statuses, strict.clone()).len(), 1);
+
+        // 15s of a 20s song only meets the fraction
+        let statuses = vec![
+            status("a.flac", 0, 20),
+            status("a.flac", 15, 20),
+            status("b.flac", 0, 20),
+        ];
+        assert_eq!(listens(statuses.clone(), ListenConfig::default()).len(), 1);
+        assert!(listens(statuses, strict.clone()).is_empty());
+
+        // 130s of a 200s song still meets both when a longer song follows
+        let statuses = vec![
+            status("a.flac", 0, 200),
+            status("a.flac", 130, 200),
+            status("b.flac", 0, 400),
+        ];
+        assert_eq!(listens(statuses, strict).len(), 1);
+    }
+}
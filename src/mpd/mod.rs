@@ -1,5 +1,113 @@
+use crate::error::{Error, Result};
+use ::mpd::Client;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
 mod listen_iterator;
 mod status_iterator;
 
-pub use listen_iterator::{ListenIterator, SongListenRecord};
-pub use status_iterator::StatusIterator;
+pub use listen_iterator::{ListenConfig, ListenIterator, SongListenRecord, ThresholdMode};
+pub use status_iterator::{
+    QueuedSong, SongStatus, StatusIterator, StickerChange, DEFAULT_HEARTBEAT,
+};
+
+/// How long to wait for MPD to accept a connection, unless configured otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Open a connection to MPD, giving up after `timeout` rather than waiting on the OS for a host
+/// that doesn't answer.
+///
+/// Each address the name resolves to is tried in turn, so `localhost` reaches an MPD bound to only
+/// one of IPv4 and IPv6.
+pub fn connect(address: &str, timeout: Duration) -> Result<TcpStream> {
+    let addrs = address.to_socket_addrs().map_err(|e| {
+        Error::Connection(format!("Failed to resolve MPD address {address}"), Some(e))
+    })?;
+    connect_any(address, addrs, timeout)
+}
+
+fn connect_any(
+    address: &str,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    let Some(e) = last_error else {
+        return Err(Error::Connection(
+            format!("No address resolved for {address}"),
+            None,
+        ));
+    };
+    let message = if e.kind() == ErrorKind::TimedOut {
+        format!(
+            "Timed out connecting to MPD at {address} after {}s",
+            timeout.as_secs_f64()
+        )
+    } else {
+        format!("Failed to connect to MPD at {address}")
+    };
+    Err(Error::Connection(message, Some(e)))
+}
+
+/// Connect to MPD and read its greeting, not hanging on a server that accepts the connection but
+/// never greets. Returns the client and a handle to its stream, e.g. to set timeouts later.
+pub fn connect_client(address: &str, timeout: Duration) -> Result<(Client<TcpStream>, TcpStream)> {
+    let stream = connect(address, timeout)?;
+
+    stream.set_read_timeout(Some(timeout))?;
+    let client = Client::new(stream.try_clone()?);
+    stream.set_read_timeout(None)?;
+
+    match client {
+        Ok(client) => Ok((client, stream)),
+        Err(::mpd::error::Error::Io(e))
+            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+        {
+            Err(Error::Connection(
+                format!(
+                    "MPD at {address} did not respond within {}s",
+                    timeout.as_secs_f64()
+                ),
+                Some(e),
+            ))
+        }
+        Err(e) => Err(Error::Connection(
+            format!("Failed to connect to MPD: {e}"),
+            None,
+        )),
+    }
+}
+
+/// Format a protocol version the way MPD reports it, e.g. `0.23.5`
+pub fn format_version(version: ::mpd::Version) -> String {
+    format!("{}.{}.{}", version.0, version.1, version.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_connect_tries_every_address() -> std::io::Result<()> {
+        // nothing listens on a port that was just released
+        let closed = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let open = listener.local_addr()?;
+
+        let timeout = Duration::from_secs(1);
+        assert!(connect_any("mpd", [closed, open], timeout).is_ok());
+        assert!(connect_any("mpd", [closed], timeout).is_err());
+        assert!(connect_any("mpd", [], timeout).is_err());
+
+        Ok(())
+    }
+}
@@ -1,44 +1,292 @@
-use anyhow::{anyhow, Context, Result};
-use mpd::{Client, Idle, Song, Subsystem};
-use std::net::ToSocketAddrs;
+use crate::error::Result;
+use mpd::{Client, Id, Idle, Song, State, Subsystem};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::TcpStream;
 use std::time::Duration;
+use tracing::warn;
+
+/// How often to read the status while waiting for player events, unless configured otherwise
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 pub struct SongStatus {
+    pub state: State,
+    /// Whether MPD's random mode is on
+    pub random: bool,
     pub song: Song,
     pub duration: Duration,
     pub elapsed: Duration,
+    /// Songs added to the queue since the previous status, if tracking the queue
+    pub queued: Vec<QueuedSong>,
+    /// Changes to the current song's sticker since the previous status, if tracking one
+    pub sticker_changes: Vec<StickerChange>,
+}
+
+/// A change to the tracked sticker of a song, e.g. a client rating it
+#[derive(Debug, Clone)]
+pub struct StickerChange {
+    pub song: Song,
+    /// The sticker's new value, `None` if it was removed
+    pub value: Option<String>,
+    /// When the change was noticed
+    pub at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A song that was added to the queue
+#[derive(Debug, Clone)]
+pub struct QueuedSong {
+    pub song: Song,
+    /// When the addition was noticed
+    pub at: chrono::DateTime<chrono::Utc>,
+}
+
+/// What is known about the queue, to tell which songs in it are new
+struct QueueState {
+    version: u32,
+    /// Ids of the songs seen in the queue
+    ids: HashSet<u32>,
 }
 
+/// The current song, along with the queue version it was read at
+struct CachedSong {
+    id: Id,
+    queue_version: u32,
+    song: Song,
+}
+
+/// Yields the player status whenever the player changes, and on every heartbeat in between.
+///
+/// MPD only reports player events (song changes, seeks, pauses), so during a long song nothing
+/// would be read for minutes. The heartbeat re-reads the status periodically to keep the elapsed
+/// time fresh; those statuses are indistinguishable from event-driven ones to consumers.
 pub struct StatusIterator {
     client: Client,
+    /// A handle on the client's socket, used to wait for events with a timeout
+    stream: TcpStream,
+    heartbeat: Duration,
+    /// Whether the status has been read yet; the first read doesn't wait for an event
+    started: bool,
+    current_song: Option<CachedSong>,
+    /// Whether to watch for songs being added to the queue
+    track_queue: bool,
+    queue: Option<QueueState>,
+    /// Songs queued since the previous status was yielded
+    queued: Vec<QueuedSong>,
+    /// The sticker to watch the current song for changes of
+    sticker: Option<String>,
+    /// Whether MPD reported a sticker change since the sticker was last read
+    sticker_changed: bool,
+    /// The current song's value of the watched sticker
+    sticker_value: Option<String>,
+    /// Sticker changes since the previous status was yielded
+    sticker_changes: Vec<StickerChange>,
 }
 
 impl StatusIterator {
-    pub fn new(socket_addr: impl AsRef<str>) -> Result<Self> {
-        let addr = socket_addr
-            .as_ref()
-            .to_socket_addrs()
-            .context("Failed to resolve MPD address")?
-            .next()
-            .context("No address resolved")?;
-        match Client::connect(addr) {
-            Ok(client) => Ok(StatusIterator { client }),
-            Err(e) => Err(anyhow!("Failed to connect to MPD: {e}")),
+    pub fn new(
+        socket_addr: impl AsRef<str>,
+        heartbeat: Duration,
+        connect_timeout: Duration,
+    ) -> Result<Self> {
+        let (client, stream) = super::connect_client(socket_addr.as_ref(), connect_timeout)?;
+
+        Ok(StatusIterator {
+            client,
+            stream,
+            heartbeat,
+            started: false,
+            current_song: None,
+            track_queue: false,
+            queue: None,
+            queued: Vec::new(),
+            sticker: None,
+            sticker_changed: false,
+            sticker_value: None,
+            sticker_changes: Vec::new(),
+        })
+    }
+
+    /// Also report songs added to the queue, see [`SongStatus::queued`]
+    pub fn with_queue_tracking(mut self) -> Self {
+        self.track_queue = true;
+        self
+    }
+
+    /// Also report changes to the `sticker` of the current song, see [`SongStatus::sticker_changes`]
+    pub fn with_sticker_tracking(mut self, sticker: impl Into<String>) -> Self {
+        self.sticker = Some(sticker.into());
+        self
+    }
+
+    /// The version of the protocol the server speaks, which follows the MPD release it runs
+    pub fn server_version(&self) -> mpd::Version {
+        self.client.version
+    }
+
+    /// Block until a player event arrives or the heartbeat interval passes.
+    ///
+    /// The `mpd` crate has no timeout on idle, so this peeks at the socket with a read timeout
+    /// instead. If nothing arrived, dropping the idle guard sends `noidle` and discards its reply.
+    fn wait(&mut self) -> Result<()> {
+        let mut subsystems = vec![Subsystem::Player];
+        if self.track_queue {
+            subsystems.push(Subsystem::Queue);
+        }
+        if self.sticker.is_some() {
+            subsystems.push(Subsystem::Sticker);
+        }
+        let guard = self.client.idle(&subsystems)?;
+
+        self.stream.set_read_timeout(Some(self.heartbeat))?;
+        let ready = self.stream.peek(&mut [0; 1]);
+        self.stream.set_read_timeout(None)?;
+
+        match ready {
+            Ok(_) => {
+                let changed = guard.get()?;
+                self.sticker_changed |= changed.contains(&Subsystem::Sticker);
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                drop(guard);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// Note the songs added to the queue since it was last checked.
+    ///
+    /// MPD doesn't say what changed in the queue, only which songs moved or were added since a
+    /// version. Added songs get a new id, so those are the songs with unseen ids. The songs already
+    /// queued when the listener starts aren't reported.
+    fn check_queue(&mut self, version: u32) -> Result<()> {
+        let ids = match self.queue.take() {
+            Some(queue) if queue.version == version => queue.ids,
+            Some(mut queue) => {
+                let at = chrono::Utc::now();
+                for song in self.client.changes(queue.version)? {
+                    if song.place.is_some_and(|place| queue.ids.insert(place.id.0)) {
+                        self.queued.push(QueuedSong { song, at });
+                    }
+                }
+                queue.ids
+            }
+            None => self
+                .client
+                .queue()?
+                .iter()
+                .filter_map(|song| song.place.map(|place| place.id.0))
+                .collect(),
+        };
+
+        self.queue = Some(QueueState { version, ids });
+        Ok(())
+    }
+
+    /// Read the watched sticker of `file`, `None` if it isn't set
+    fn read_sticker(&mut self, file: &str) -> Result<Option<String>> {
+        let Some(sticker) = &self.sticker else {
+            return Ok(None);
+        };
+        match self.client.sticker("song", file, sticker) {
+            Ok(value) => Ok(Some(value)),
+            Err(::mpd::error::Error::Io(e)) => Err(e.into()),
+            // MPD answers a missing sticker with an error
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Note a change of the current song's sticker, if MPD reported one.
+    ///
+    /// MPD doesn't say which song's stickers changed, so the current song's is re-read and compared.
+    /// A new song's sticker is only read to have something to compare with.
+    fn check_sticker(&mut self, new_song: bool) -> Result<()> {
+        let changed = std::mem::take(&mut self.sticker_changed);
+        if self.sticker.is_none() || !(new_song || changed) {
+            return Ok(());
+        }
+        let Some(song) = self.current_song.as_ref().map(|cached| cached.song.clone()) else {
+            return Ok(());
+        };
+
+        let value = self.read_sticker(&song.file)?;
+        if !new_song && value != self.sticker_value {
+            self.sticker_changes.push(StickerChange {
+                song,
+                value: value.clone(),
+                at: chrono::Utc::now(),
+            });
         }
+        self.sticker_value = value;
+        Ok(())
     }
 
+    /// Read the player status and current song.
+    ///
+    /// Ideally `status` and `currentsong` would be sent as a single command list, but the `mpd`
+    /// crate keeps its protocol internals private and only uses command lists within its own
+    /// methods. Instead, the current song is cached by its queue id and the queue version, both of
+    /// which `status` reports, so `currentsong` is only needed when either changes. Most wakeups
+    /// (seeks, pauses) are then a single round-trip.
     fn get_status(&mut self) -> Option<SongStatus> {
         let status = self.client.status().ok()?;
+
+        // songs queued while stopped are reported once playback starts
+        if self.track_queue {
+            if let Err(e) = self.check_queue(status.queue_version) {
+                warn!("Failed to read queue changes: {e}");
+            }
+        }
+
+        // At the end of the queue MPD stops and no longer reports a song, so report the stop along
+        // with the last song seen. Further stopped statuses are dropped until playback resumes.
+        if status.state == State::Stop {
+            return self.current_song.take().map(|cached| SongStatus {
+                state: State::Stop,
+                random: status.random,
+                song: cached.song,
+                duration: Duration::ZERO,
+                elapsed: Duration::ZERO,
+                queued: std::mem::take(&mut self.queued),
+                sticker_changes: std::mem::take(&mut self.sticker_changes),
+            });
+        }
+
         let elapsed = status.elapsed?;
         let duration = status.duration?;
+        let id = status.song?.id;
+
+        let cached = self
+            .current_song
+            .as_ref()
+            .is_some_and(|c| c.id == id && c.queue_version == status.queue_version);
+        if !cached {
+            let song = self.client.currentsong().ok()??;
 
-        let song = self.client.currentsong().ok()??;
+            // the player moved on between the two reads, a new player event is already queued
+            if song.place.map(|place| place.id) != Some(id) {
+                return None;
+            }
+
+            self.current_song = Some(CachedSong {
+                id,
+                queue_version: status.queue_version,
+                song,
+            });
+        }
+        if let Err(e) = self.check_sticker(!cached) {
+            warn!("Failed to read the sticker of the current song: {e}");
+        }
 
         Some(SongStatus {
+            state: status.state,
+            random: status.random,
             duration,
-            song,
+            song: self.current_song.as_ref()?.song.clone(),
             elapsed,
+            queued: std::mem::take(&mut self.queued),
+            sticker_changes: std::mem::take(&mut self.sticker_changes),
         })
     }
 }
@@ -48,7 +296,11 @@ impl Iterator for StatusIterator {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            self.client.idle(&[Subsystem::Player]).ok()?;
+            if self.started {
+                self.wait().ok()?;
+            }
+            self.started = true;
+
             if let Some(status) = self.get_status() {
                 return Some(status);
             }
@@ -1,3 +1,7 @@
+pub mod model;
 pub mod sqlite;
+pub mod store;
 
+pub use model::{PlayRecord, QueuedRecord, Reaction, ReactionRecord, TagFilter, TimeInterval};
 pub use sqlite::MusicDb;
+pub use store::{BufferConfig, PlayStore};
@@ -0,0 +1,503 @@
+use crate::mpd::{QueuedSong, SongListenRecord, StickerChange};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayRecord {
+    pub timestamp: i64, // Unix timestamp
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub date: Option<String>,
+    pub other_tags: HashMap<String, Vec<String>>,
+    pub song_duration_seconds: Option<u64>,
+    /// Name of the MPD instance the play was recorded from
+    pub source: Option<String>,
+    /// Whether MPD's random mode was on, unknown for plays recorded before it was tracked
+    pub random: Option<bool>,
+    /// The song's file in MPD's music directory or its stream URL, unknown for plays recorded
+    /// before it was stored
+    pub file: Option<String>,
+}
+
+/// A song added to the queue, which may or may not have been played
+#[derive(Debug, PartialEq)]
+pub struct QueuedRecord {
+    pub timestamp: i64, // Unix timestamp
+    pub file: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub song_duration_seconds: Option<u64>,
+    /// Name of the MPD instance the song was queued on
+    pub source: Option<String>,
+}
+
+/// The last value of a song's tag, like [`PlayRecord::from_listen`] picks it
+fn last_tag(song: &mpd::Song, name: &str) -> Option<String> {
+    song.tags
+        .iter()
+        .rev()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.clone())
+}
+
+impl From<QueuedSong> for QueuedRecord {
+    fn from(queued: QueuedSong) -> Self {
+        let song = queued.song;
+
+        QueuedRecord {
+            timestamp: queued.at.timestamp(),
+            title: song.title.clone().or_else(|| last_tag(&song, "Title")),
+            artist: song.artist.clone().or_else(|| last_tag(&song, "Artist")),
+            album: last_tag(&song, "Album"),
+            album_artist: last_tag(&song, "AlbumArtist"),
+            song_duration_seconds: song.duration.map(|d| d.as_secs()),
+            source: None,
+            file: song.file,
+        }
+    }
+}
+
+/// How a song was rated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reaction {
+    Love,
+    Ban,
+}
+
+impl Reaction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reaction::Love => "love",
+            Reaction::Ban => "ban",
+        }
+    }
+}
+
+/// A song being loved or banned, or its reaction being taken back
+#[derive(Debug, PartialEq)]
+pub struct ReactionRecord {
+    pub timestamp: i64, // Unix timestamp
+    pub file: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub song_duration_seconds: Option<u64>,
+    /// Name of the MPD instance the song was rated on
+    pub source: Option<String>,
+    /// `None` when the song is neither loved nor banned anymore
+    pub reaction: Option<Reaction>,
+}
+
+impl ReactionRecord {
+    pub fn new(change: StickerChange, reaction: Option<Reaction>) -> Self {
+        let song = change.song;
+
+        ReactionRecord {
+            timestamp: change.at.timestamp(),
+            title: song.title.clone().or_else(|| last_tag(&song, "Title")),
+            artist: song.artist.clone().or_else(|| last_tag(&song, "Artist")),
+            album: last_tag(&song, "Album"),
+            album_artist: last_tag(&song, "AlbumArtist"),
+            song_duration_seconds: song.duration.map(|d| d.as_secs()),
+            source: None,
+            file: song.file,
+            reaction,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TimeInterval {
+    Week,
+    Month,
+    Year,
+    AllTime,
+    /// Everything since the given Unix timestamp
+    Since(i64),
+}
+
+impl TimeInterval {
+    fn to_seconds(self) -> Option<i64> {
+        match self {
+            TimeInterval::Week => Some(7 * 24 * 60 * 60),
+            TimeInterval::Month => Some(30 * 24 * 60 * 60),
+            TimeInterval::Year => Some(365 * 24 * 60 * 60),
+            TimeInterval::AllTime | TimeInterval::Since(_) => None,
+        }
+    }
+
+    /// Get the Unix timestamp this interval starts at, or `None` for all time
+    pub fn cutoff_timestamp(self) -> Option<i64> {
+        if let TimeInterval::Since(timestamp) = self {
+            return Some(timestamp);
+        }
+
+        self.to_seconds().map(|seconds| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64
+                - seconds
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ArtistStats {
+    pub artist_name: String,
+    pub play_count: i64,
+    pub total_minutes: f64,
+}
+
+#[derive(Debug)]
+pub struct SongStats {
+    pub title: String,
+    pub artist_name: String,
+    pub play_count: i64,
+    pub total_minutes: f64,
+    /// The file of the song's latest play that has one
+    pub file: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AlbumStats {
+    pub album: String,
+    pub artist_name: String,
+    pub play_count: i64,
+    pub total_minutes: f64,
+}
+
+/// Listening on either weekdays or weekends
+#[derive(Debug, Default)]
+pub struct WeekdayStats {
+    pub play_count: i64,
+    pub total_minutes: f64,
+}
+
+/// Overall listening in an interval, for averages and projections
+#[derive(Debug, Default)]
+pub struct ActivityStats {
+    pub play_count: i64,
+    pub total_minutes: f64,
+    /// Distinct calendar days with plays
+    pub active_days: i64,
+    /// When the interval effectively started: its cutoff, or the first recorded play if that's
+    /// later. `None` when there are no plays at all.
+    pub since: Option<i64>,
+}
+
+impl ActivityStats {
+    /// Average plays and minutes per day with any plays, `None` without any
+    pub fn per_active_day(&self) -> Option<(f64, f64)> {
+        (self.active_days > 0).then(|| {
+            let days = self.active_days as f64;
+            (self.play_count as f64 / days, self.total_minutes / days)
+        })
+    }
+
+    /// Naively extrapolate the plays and minutes per day since [`Self::since`] to the whole
+    /// calendar year of `now`, `None` without any plays. Less than a day counts as a day, so a
+    /// fresh start doesn't project absurd numbers.
+    pub fn projected_year(&self, now: DateTime<Utc>) -> Option<(i64, f64)> {
+        let since = self.since.filter(|_| self.play_count > 0)?;
+        let elapsed_days = ((now.timestamp() - since) as f64 / 86_400.0).max(1.0);
+        let year = now.year();
+        let days_in_year = (NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+            - NaiveDate::from_ymd_opt(year, 1, 1)?)
+        .num_days() as f64;
+
+        let scale = days_in_year / elapsed_days;
+        Some((
+            (self.play_count as f64 * scale).round() as i64,
+            self.total_minutes * scale,
+        ))
+    }
+}
+
+/// How one artist fared in a head-to-head comparison
+#[derive(Debug)]
+pub struct ArtistStanding {
+    pub artist_name: String,
+    pub play_count: i64,
+    pub total_minutes: f64,
+    pub first_played: Option<i64>,
+    pub last_played: Option<i64>,
+    /// Position in the top artists list, or `None` if the artist wasn't played
+    pub rank: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct ArtistComparison {
+    pub first: ArtistStanding,
+    pub second: ArtistStanding,
+}
+
+#[derive(Debug)]
+pub struct GenreStats {
+    pub genre: String,
+    /// Whether the genre was looked up for the artist rather than taken from the song's tags
+    pub inferred: bool,
+    pub play_count: i64,
+    pub total_minutes: f64,
+}
+
+/// Split a multi-artist credit ("A feat. B", "A / B / C") into the individual artists.
+///
+/// Separators are matched ignoring case, and only where followed by whitespace so that e.g.
+/// "AC/DC" stays whole. Each artist is listed once, in order of appearance.
+pub fn split_artists(credit: &str, separators: &[String]) -> Vec<String> {
+    let mut parts = vec![credit];
+    for separator in separators.iter().filter(|s| !s.is_empty()) {
+        parts = parts
+            .into_iter()
+            .flat_map(|part| split_on(part, separator))
+            .collect();
+    }
+
+    let mut artists: Vec<String> = Vec::new();
+    for part in parts.into_iter().map(str::trim).filter(|p| !p.is_empty()) {
+        if !artists.iter().any(|artist| artist == part) {
+            artists.push(part.to_string());
+        }
+    }
+    artists
+}
+
+fn split_on<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (i, _) in text.char_indices() {
+        let Some(candidate) = text.get(i..i + separator.len()) else {
+            continue;
+        };
+        let followed_by_space = text[i + separator.len()..]
+            .chars()
+            .next()
+            .is_some_and(char::is_whitespace);
+        if i >= start && followed_by_space && candidate.eq_ignore_ascii_case(separator) {
+            parts.push(&text[start..i]);
+            start = i + separator.len();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Which tags are left out of `other_tags` when recording a listen
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TagFilter {
+    /// Tags to drop by name
+    pub drop: Vec<String>,
+    /// Also drop all sorting variants, e.g. "AlbumArtistSort"
+    pub drop_sort_variants: bool,
+}
+
+impl Default for TagFilter {
+    fn default() -> Self {
+        // don't really see a reason to track these
+        Self {
+            drop: ["duration", "Added", "Format", "Track", "Disc"]
+                .map(String::from)
+                .to_vec(),
+            drop_sort_variants: true,
+        }
+    }
+}
+
+impl PlayRecord {
+    pub fn from_listen(record: SongListenRecord, filter: &TagFilter) -> Self {
+        let mut tags_map: HashMap<String, Vec<String>> = HashMap::new();
+
+        for (key, value) in record.song.tags {
+            tags_map.entry(key).or_default().push(value);
+        }
+
+        for tag in &filter.drop {
+            tags_map.remove(tag);
+        }
+        if filter.drop_sort_variants {
+            tags_map.retain(|key, _value| !key.ends_with("Sort"));
+        }
+
+        // pull top-level concepts out
+        let tag_title = tags_map.remove("Title").and_then(|mut v| v.pop());
+        let tag_artist = tags_map.remove("Artist").and_then(|mut v| v.pop());
+        let album = tags_map.remove("Album").and_then(|mut v| v.pop());
+        let album_artist = tags_map.remove("AlbumArtist").and_then(|mut v| v.pop());
+        let date = tags_map.remove("Date").and_then(|mut v| v.pop());
+        let song_duration_seconds = record
+            .duration
+            .or(record.song.duration)
+            .map(|d| d.as_secs());
+
+        PlayRecord {
+            timestamp: record.start.timestamp(),
+            title: record.song.title.or(tag_title),
+            artist: record.song.artist.or(tag_artist),
+            album,
+            album_artist,
+            date,
+            other_tags: tags_map,
+            song_duration_seconds,
+            source: None,
+            random: Some(record.random),
+            file: Some(record.song.file).filter(|file| !file.is_empty()),
+        }
+    }
+
+    /// Run a stored play through the same extraction as a new listen, to apply changed rules to it
+    pub fn reextract(self, filter: &TagFilter) -> Self {
+        let mut tags: Vec<(String, String)> = self
+            .other_tags
+            .into_iter()
+            .flat_map(|(key, values)| values.into_iter().map(move |value| (key.clone(), value)))
+            .collect();
+        let top_level = [("AlbumArtist", self.album_artist), ("Date", self.date)];
+        for (key, value) in top_level {
+            if let Some(value) = value {
+                tags.push((key.to_string(), value));
+            }
+        }
+
+        let listen = SongListenRecord {
+            // title and artist go where mpd puts them, outside the tags, so a drop list keeps them
+            song: mpd::Song {
+                title: self.title,
+                artist: self.artist,
+                tags,
+                ..Default::default()
+            },
+            start: chrono::DateTime::from_timestamp(self.timestamp, 0).unwrap_or_default(),
+            random: false,
+            duration: self.song_duration_seconds.map(Duration::from_secs),
+        };
+        // titles, artists and albums stay as recorded
+        PlayRecord {
+            album: self.album,
+            source: self.source,
+            random: self.random,
+            file: self.file,
+            ..PlayRecord::from_listen(listen, filter)
+        }
+    }
+}
+
+impl From<SongListenRecord> for PlayRecord {
+    fn from(record: SongListenRecord) -> Self {
+        PlayRecord::from_listen(record, &TagFilter::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity_stats() {
+        let now = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let stats = ActivityStats {
+            play_count: 100,
+            total_minutes: 300.0,
+            active_days: 4,
+            since: Some(now.timestamp() - 10 * 86_400),
+        };
+        assert_eq!(stats.per_active_day(), Some((25.0, 75.0)));
+        // 2024 is a leap year
+        assert_eq!(stats.projected_year(now), Some((3660, 10980.0)));
+
+        // plays from the last hour count as a day's worth
+        let stats = ActivityStats {
+            since: Some(now.timestamp() - 3600),
+            ..stats
+        };
+        assert_eq!(stats.projected_year(now), Some((36600, 109800.0)));
+
+        let empty = ActivityStats::default();
+        assert_eq!(empty.per_active_day(), None);
+        assert_eq!(empty.projected_year(now), None);
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let listen = || SongListenRecord {
+            song: mpd::Song {
+                tags: [
+                    ("Track", "3"),
+                    ("Disc", "1"),
+                    ("Format", "44100:16:2"),
+                    ("ArtistSort", "Beatles, The"),
+                    ("Genre", "Rock"),
+                ]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .to_vec(),
+                ..Default::default()
+            },
+            start: chrono::Utc::now(),
+            random: true,
+            duration: None,
+        };
+
+        let mut tags: Vec<_> = PlayRecord::from(listen()).other_tags.into_keys().collect();
+        tags.sort();
+        assert_eq!(tags, vec!["Genre"]);
+        assert_eq!(PlayRecord::from(listen()).random, Some(true));
+
+        let filter = TagFilter {
+            drop: vec!["Format".to_string()],
+            drop_sort_variants: false,
+        };
+        let mut tags: Vec<_> = PlayRecord::from_listen(listen(), &filter)
+            .other_tags
+            .into_keys()
+            .collect();
+        tags.sort();
+        assert_eq!(tags, vec!["ArtistSort", "Disc", "Genre", "Track"]);
+    }
+
+    #[test]
+    fn test_observed_duration_preferred() {
+        let listen = |duration| SongListenRecord {
+            song: mpd::Song {
+                duration: Some(Duration::from_secs(180)),
+                ..Default::default()
+            },
+            start: chrono::Utc::now(),
+            random: false,
+            duration,
+        };
+
+        let record = PlayRecord::from(listen(Some(Duration::from_secs(200))));
+        assert_eq!(record.song_duration_seconds, Some(200));
+        // the song's duration is only a fallback
+        let record = PlayRecord::from(listen(None));
+        assert_eq!(record.song_duration_seconds, Some(180));
+    }
+
+    #[test]
+    fn test_split_artists() {
+        let separators = ["feat.", "&", "/", ","].map(String::from);
+
+        for (credit, expected) in [
+            ("Artist A", vec!["Artist A"]),
+            ("Artist A feat. Artist B", vec!["Artist A", "Artist B"]),
+            ("Artist A Feat. Artist B", vec!["Artist A", "Artist B"]),
+            ("A / B / C", vec!["A", "B", "C"]),
+            ("A, B & C", vec!["A", "B", "C"]),
+            ("A feat. B & A", vec!["A", "B"]),
+            ("AC/DC", vec!["AC/DC"]),
+            ("Beyoncé feat. Jay-Z", vec!["Beyoncé", "Jay-Z"]),
+        ] {
+            assert_eq!(split_artists(credit, &separators), expected, "{credit}");
+        }
+    }
+}
@@ -1,107 +1,88 @@
-use crate::mpd::SongListenRecord;
+use crate::mpd::SongStatus;
+use crate::persistence::model::{
+    split_artists, ActivityStats, AlbumStats, ArtistComparison, ArtistStanding, ArtistStats,
+    GenreStats, PlayRecord, QueuedRecord, ReactionRecord, SongStats, TagFilter, TimeInterval,
+    WeekdayStats,
+};
+use crate::persistence::store::PlayStore;
+use chrono_tz::Tz;
 use include_dir::{include_dir, Dir};
-use rusqlite::{params, Connection, Result};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension, Result};
 use rusqlite_migration::Migrations;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::LazyLock;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct PlayRecord {
-    pub timestamp: i64, // Unix timestamp
-    pub title: Option<String>,
-    pub artist: Option<String>,
-    pub album: Option<String>,
-    pub album_artist: Option<String>,
-    pub date: Option<String>,
-    pub other_tags: HashMap<String, Vec<String>>,
-    pub song_duration_seconds: Option<u64>,
+pub struct MusicDb {
+    conn: Connection,
+    /// Separators to split artist credits on, splitting is disabled when empty
+    artist_separators: Vec<String>,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum TimeInterval {
-    Week,
-    Month,
-    Year,
-    AllTime,
-}
+/// Retry an operation that failed because another connection held a lock on the database.
+///
+/// The busy timeout already waits for locks, but a long read (e.g. a big export) can outlast it.
+/// `op` must be safe to rerun, i.e. run in its own transaction.
+fn retry_busy<T>(mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    const MAX_RETRIES: u32 = 5;
+    const INITIAL_BACKOFF: Duration = Duration::from_millis(25);
 
-impl TimeInterval {
-    fn to_seconds(&self) -> Option<i64> {
-        match self {
-            TimeInterval::Week => Some(7 * 24 * 60 * 60),
-            TimeInterval::Month => Some(30 * 24 * 60 * 60),
-            TimeInterval::Year => Some(365 * 24 * 60 * 60),
-            TimeInterval::AllTime => None,
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1.. {
+        match op() {
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if attempt <= MAX_RETRIES
+                    && matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) =>
+            {
+                warn!(
+                    "database is locked, retrying in {backoff:?} (attempt {attempt}/{MAX_RETRIES})"
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
         }
     }
+    unreachable!()
 }
 
-#[derive(Debug)]
-pub struct ArtistStats {
-    pub artist_name: String,
-    pub play_count: i64,
-    pub total_minutes: f64,
-}
-
-#[derive(Debug)]
-pub struct SongStats {
-    pub title: String,
-    pub artist_name: String,
-    pub play_count: i64,
-    pub total_minutes: f64,
-}
-
-#[derive(Debug)]
-pub struct AlbumStats {
-    pub album: String,
-    pub artist_name: String,
-    pub play_count: i64,
-    pub total_minutes: f64,
+/// SQLite treats a negative LIMIT as no limit
+fn sql_limit(limit: Option<usize>) -> i64 {
+    limit.map_or(-1, |limit| limit as i64)
 }
 
-impl From<SongListenRecord> for PlayRecord {
-    fn from(record: SongListenRecord) -> Self {
-        let mut tags_map: HashMap<String, Vec<String>> = HashMap::new();
+/// The columns [`MusicDb::read_play`] reads a play from
+const PLAY_COLUMNS: &str =
+    "id, timestamp, title, artist, album, album_artist, date, song_duration_seconds, source, random, file";
 
-        for (key, value) in record.song.tags {
-            tags_map.entry(key).or_default().push(value);
-        }
-
-        // don't really see a reason to track these
-        tags_map.remove("duration");
-        tags_map.remove("Added");
-        tags_map.remove("Format");
-        tags_map.remove("Track");
-        tags_map.remove("Disc");
+/// How many status snapshots to keep, older ones are deleted as new ones are recorded
+const MAX_STATUS_SNAPSHOTS: i64 = 10_000;
 
-        // also remove all keys that are sorting variants, e.g. "AlbumArtistSort"
-        tags_map.retain(|key, _value| !key.ends_with("Sort"));
+/// Longer durations come from broken tags, they would dominate the minute totals
+const MAX_SONG_DURATION_SECONDS: u64 = 24 * 60 * 60;
 
-        // pull top-level concepts out
-        let tag_title = tags_map.remove("Title").and_then(|mut v| v.pop());
-        let tag_artist = tags_map.remove("Artist").and_then(|mut v| v.pop());
-        let album = tags_map.remove("Album").and_then(|mut v| v.pop());
-        let album_artist = tags_map.remove("AlbumArtist").and_then(|mut v| v.pop());
-        let date = tags_map.remove("Date").and_then(|mut v| v.pop());
-        let song_duration_seconds = record.song.duration.map(|d| d.as_secs());
-
-        PlayRecord {
-            timestamp: record.start.timestamp(),
-            title: record.song.title.or(tag_title),
-            artist: record.song.artist.or(tag_artist),
-            album,
-            album_artist,
-            date,
-            other_tags: tags_map,
-            song_duration_seconds,
-        }
-    }
+/// The `YYYY-MM-DD` date of a Unix timestamp in the given timezone, or the system's
+fn local_date(timestamp: i64, timezone: Option<Tz>) -> Option<String> {
+    let time = chrono::DateTime::from_timestamp(timestamp, 0)?;
+    let date = match timezone {
+        Some(timezone) => time.with_timezone(&timezone).date_naive(),
+        None => time.with_timezone(&chrono::Local).date_naive(),
+    };
+    Some(date.format("%Y-%m-%d").to_string())
 }
 
-pub struct MusicDb {
-    conn: Connection,
+/// Make `local_date(timestamp)` available to queries, for bucketing plays by calendar day
+fn register_local_date(conn: &Connection, timezone: Option<Tz>) -> Result<()> {
+    conn.create_scalar_function(
+        "local_date",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| Ok(local_date(ctx.get(0)?, timezone)),
+    )
 }
 
 static MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/migrations");
@@ -110,27 +91,208 @@ static MIGRATIONS: LazyLock<Migrations<'static>> =
 
 impl MusicDb {
     /// Create a new database connection and initialize schema
-    pub fn new<P: AsRef<Path>>(db_path: P) -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(db_path: P) -> crate::error::Result<Self> {
         let mut conn = Connection::open(db_path)?;
 
-        MIGRATIONS.to_latest(&mut conn).unwrap();
+        MIGRATIONS.to_latest(&mut conn)?;
+        register_local_date(&conn, None)?;
+
+        Ok(MusicDb {
+            conn,
+            artist_separators: Vec::new(),
+        })
+    }
+
+    /// Open an existing database for reading only, so long-running readers don't contend with the
+    /// listener's writes. Doesn't migrate the schema, open it with [`MusicDb::new`] first.
+    pub fn open_read_only<P: AsRef<Path>>(db_path: P) -> crate::error::Result<Self> {
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        register_local_date(&conn, None)?;
+
+        Ok(MusicDb {
+            conn,
+            artist_separators: Vec::new(),
+        })
+    }
+
+    /// Bucket plays into days in `timezone` rather than the system's timezone
+    pub fn with_timezone(self, timezone: Tz) -> Result<Self> {
+        register_local_date(&self.conn, Some(timezone))?;
+        Ok(self)
+    }
+
+    /// Also record each artist of a multi-artist credit in `plays_artists` when logging plays
+    pub fn with_artist_separators(mut self, separators: Vec<String>) -> Self {
+        self.artist_separators = separators;
+        self
+    }
+
+    /// The number of migrations applied to the database
+    pub fn schema_version(&self) -> Result<usize> {
+        // rusqlite_migration tracks the version in user_version
+        self.conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+    }
+
+    /// Whether SQLite could only open the database for reading, e.g. due to file permissions
+    pub fn is_read_only(&self) -> Result<bool> {
+        self.conn.is_readonly(rusqlite::MAIN_DB)
+    }
 
-        Ok(MusicDb { conn })
+    /// The Unix timestamp of the most recent play, if any were recorded
+    pub fn latest_play_timestamp(&self) -> Result<Option<i64>> {
+        self.conn
+            .query_row("SELECT MAX(timestamp) FROM plays", [], |row| row.get(0))
     }
 
     /// Log a play record
     pub fn log_play(&self, record: &PlayRecord) -> Result<i64> {
+        retry_busy(|| {
+            let tx = self.conn.unchecked_transaction()?;
+            let play_id = self.insert_play(record)?;
+            tx.commit()?;
+            Ok(play_id)
+        })
+    }
+
+    /// Log several play records in a single transaction
+    pub fn log_plays(&self, records: &[PlayRecord]) -> Result<()> {
+        retry_busy(|| {
+            let tx = self.conn.unchecked_transaction()?;
+            for record in records {
+                self.insert_play(record)?;
+            }
+            tx.commit()
+        })
+    }
+
+    /// Log a song added to the queue, returning its id
+    pub fn log_queued(&self, record: &QueuedRecord) -> Result<i64> {
+        retry_busy(|| {
+            self.conn.execute(
+                "INSERT INTO queued (timestamp, file, title, artist, album, album_artist, song_duration_seconds, source)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    record.timestamp,
+                    record.file,
+                    record.title,
+                    record.artist,
+                    record.album,
+                    record.album_artist,
+                    record.song_duration_seconds,
+                    record.source
+                ],
+            )
+        })?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Record a song's reaction, replacing any earlier one. Repeating a reaction keeps the time it
+    /// was first given, and no reaction removes it.
+    pub fn record_reaction(&self, record: &ReactionRecord) -> Result<()> {
+        let Some(reaction) = record.reaction else {
+            retry_busy(|| {
+                self.conn.execute(
+                    "DELETE FROM reactions WHERE file = ?1",
+                    params![record.file],
+                )
+            })?;
+            return Ok(());
+        };
+
+        retry_busy(|| {
+            self.conn.execute(
+                "INSERT INTO reactions (file, reaction, timestamp, title, artist, album, album_artist, song_duration_seconds, source)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (file) DO UPDATE SET
+                     reaction = excluded.reaction,
+                     timestamp = excluded.timestamp,
+                     title = excluded.title,
+                     artist = excluded.artist,
+                     album = excluded.album,
+                     album_artist = excluded.album_artist,
+                     song_duration_seconds = excluded.song_duration_seconds,
+                     source = excluded.source
+                 WHERE reaction <> excluded.reaction",
+                params![
+                    record.file,
+                    reaction.as_str(),
+                    record.timestamp,
+                    record.title,
+                    record.artist,
+                    record.album,
+                    record.album_artist,
+                    record.song_duration_seconds,
+                    record.source
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Get the loved songs, most recently loved first. The timestamp is when the song was loved.
+    pub fn loved_tracks(&self) -> Result<Vec<PlayRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, title, artist, album, album_artist, song_duration_seconds, source, file
+             FROM reactions
+             WHERE reaction = 'love'
+             ORDER BY timestamp DESC, file",
+        )?;
+
+        let tracks = stmt
+            .query_map([], |row| {
+                Ok(PlayRecord {
+                    timestamp: row.get(0)?,
+                    title: row.get(1)?,
+                    artist: row.get(2)?,
+                    album: row.get(3)?,
+                    album_artist: row.get(4)?,
+                    date: None,
+                    other_tags: HashMap::new(),
+                    song_duration_seconds: row.get(5)?,
+                    source: row.get(6)?,
+                    random: None,
+                    file: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(tracks)
+    }
+
+    fn insert_play(&self, record: &PlayRecord) -> Result<i64> {
+        // an unknown duration (e.g. a stream) is stored as 0, which adds nothing to the totals
+        let song_duration_seconds = match record.song_duration_seconds {
+            Some(seconds) if seconds > MAX_SONG_DURATION_SECONDS => {
+                warn!(
+                    "ignoring implausible duration of {seconds}s for {:?} by {:?}",
+                    record.title, record.artist
+                );
+                0
+            }
+            seconds => seconds.unwrap_or(0),
+        };
+        // the schema doesn't allow empty names, they are as good as missing
+        let title = record.title.as_deref().filter(|title| !title.is_empty());
+        let artist = record.artist.as_deref().filter(|artist| !artist.is_empty());
+
         self.conn.execute(
-            "INSERT INTO plays (timestamp, title, artist, album, album_artist, date, song_duration_seconds)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO plays (timestamp, title, artist, album, album_artist, date, song_duration_seconds, source, random, file, inserted_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, CAST(strftime('%s', 'now') AS INTEGER))",
             params![
                 record.timestamp,
-                record.title,
-                record.artist,
+                title,
+                artist,
                 record.album,
                 record.album_artist,
                 record.date,
-                record.song_duration_seconds
+                song_duration_seconds,
+                record.source,
+                record.random,
+                record.file
             ],
         )?;
         let play_id = self.conn.last_insert_rowid();
@@ -144,10 +306,25 @@ impl MusicDb {
             }
         }
 
+        if let Some(artist) = record
+            .artist
+            .as_deref()
+            .filter(|_| !self.artist_separators.is_empty())
+        {
+            for artist in split_artists(artist, &self.artist_separators) {
+                self.conn.execute(
+                    "INSERT INTO plays_artists (play_id, artist) VALUES (?1, ?2)",
+                    params![play_id, artist],
+                )?;
+            }
+        }
+
         Ok(play_id)
     }
 
     /// Get top artists by play count
+    #[allow(dead_code)]
+    #[deprecated(note = "use `get_top_artists`, which also reports minutes and supports intervals")]
     pub fn top_artists(&self, limit: usize) -> Result<Vec<(String, i64)>> {
         let mut stmt = self.conn.prepare(
             "SELECT artist, COUNT(*) as play_count
@@ -166,6 +343,8 @@ impl MusicDb {
     }
 
     /// Get top albums by play count
+    #[allow(dead_code)]
+    #[deprecated(note = "use `get_top_albums`, which also reports minutes and supports intervals")]
     pub fn top_albums(&self, limit: usize) -> Result<Vec<(String, String, i64)>> {
         let mut stmt = self.conn.prepare(
             "SELECT album, COALESCE(album_artist, artist) as artist, COUNT(*) as play_count
@@ -186,190 +365,2451 @@ impl MusicDb {
     }
 
     fn get_cutoff_timestamp(&self, interval: TimeInterval) -> Option<i64> {
-        interval.to_seconds().map(|seconds| {
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64
-                - seconds
-        })
+        interval.cutoff_timestamp()
     }
 
-    pub fn get_top_artists(&self, interval: TimeInterval) -> Result<Vec<ArtistStats>> {
-        let cutoff = self.get_cutoff_timestamp(interval);
+    fn get_state(&self, key: &str) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT value FROM state WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+    }
 
-        let query = if let Some(cutoff_ts) = cutoff {
-            format!(
-                "SELECT
-                    COALESCE(album_artist, artist) AS artist_name,
-                    COUNT(*) AS play_count,
-                    ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
-                FROM plays
-                WHERE timestamp >= {}
-                GROUP BY artist_name
-                ORDER BY total_minutes DESC",
-                cutoff_ts
+    fn set_state(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO state (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Get the Unix timestamp of the previous `query` invocation, if there was one
+    pub fn last_query_timestamp(&self) -> Result<Option<i64>> {
+        Ok(self
+            .get_state("last_query_timestamp")?
+            .and_then(|value| value.parse().ok()))
+    }
+
+    /// Record the Unix timestamp of a `query` invocation
+    pub fn set_last_query_timestamp(&self, timestamp: i64) -> Result<()> {
+        self.set_state("last_query_timestamp", &timestamp.to_string())
+    }
+
+    /// Get when the play count stickers were last caught up with for `source`, and their counts
+    /// then by file. `None` if they never were.
+    pub fn playcount_baseline(
+        &self,
+        source: Option<&str>,
+    ) -> Result<Option<(i64, HashMap<String, u64>)>> {
+        let source = source.unwrap_or_default();
+        let Some(taken_at) = self
+            .get_state(&format!("last_catch_up:{source}"))?
+            .and_then(|value| value.parse().ok())
+        else {
+            return Ok(None);
+        };
+
+        let counts = self
+            .conn
+            .prepare("SELECT file, playcount FROM playcount_baselines WHERE source = ?1")?
+            .query_map(params![source], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        Ok(Some((taken_at, counts)))
+    }
+
+    /// Log the plays a catch-up backfilled and replace the play count stickers caught up with for
+    /// `source`, as of `taken_at`. Both happen in one transaction, so a failure can't leave plays
+    /// backfilled against the old counts, to be backfilled again.
+    pub fn record_catch_up(
+        &self,
+        source: Option<&str>,
+        taken_at: i64,
+        counts: &[(String, u64)],
+        backfilled: &[PlayRecord],
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+
+        for record in backfilled {
+            self.insert_play(record)?;
+        }
+
+        let source = source.unwrap_or_default();
+        self.conn.execute(
+            "DELETE FROM playcount_baselines WHERE source = ?1",
+            params![source],
+        )?;
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO playcount_baselines (source, file, playcount) VALUES (?1, ?2, ?3)",
+        )?;
+        for (file, playcount) in counts {
+            stmt.execute(params![source, file, playcount])?;
+        }
+        self.set_state(&format!("last_catch_up:{source}"), &taken_at.to_string())?;
+
+        tx.commit()
+    }
+
+    /// Count the plays of each file recorded from `source` since `since`
+    pub fn plays_by_file_since(
+        &self,
+        source: Option<&str>,
+        since: i64,
+    ) -> Result<HashMap<String, u64>> {
+        self.conn
+            .prepare(
+                "SELECT file, COUNT(*) FROM plays
+                 WHERE timestamp >= ?1 AND source IS ?2 AND file IS NOT NULL
+                 GROUP BY file",
+            )?
+            .query_map(params![since, source], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    /// Record the start of a listener session, returning its id
+    pub fn start_session(
+        &self,
+        source: Option<&str>,
+        server_version: Option<&str>,
+        timestamp: i64,
+    ) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO listener_sessions (source, server_version, started_at, last_heartbeat)
+             VALUES (?1, ?2, ?3, ?3)",
+            params![source, server_version, timestamp],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Record that the listener for a session was still alive at `timestamp`
+    pub fn record_heartbeat(&self, session: i64, timestamp: i64) -> Result<()> {
+        retry_busy(|| {
+            self.conn.execute(
+                "UPDATE listener_sessions SET last_heartbeat = ?2 WHERE id = ?1",
+                params![session, timestamp],
             )
-        } else {
+        })?;
+        Ok(())
+    }
+
+    /// Record a status read from MPD during a session, for diagnosing missed listens
+    pub fn record_status_snapshot(
+        &self,
+        session: i64,
+        timestamp: i64,
+        status: &SongStatus,
+    ) -> Result<()> {
+        let state = match status.state {
+            mpd::State::Play => "play",
+            mpd::State::Pause => "pause",
+            mpd::State::Stop => "stop",
+        };
+
+        retry_busy(|| {
+            let tx = self.conn.unchecked_transaction()?;
+            tx.execute(
+                "INSERT INTO status_snapshots
+                    (session_id, timestamp, file, state, random, elapsed_seconds, duration_seconds)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    session,
+                    timestamp,
+                    status.song.file,
+                    state,
+                    status.random,
+                    status.elapsed.as_secs_f64(),
+                    status.duration.as_secs_f64()
+                ],
+            )?;
+            tx.execute(
+                "DELETE FROM status_snapshots WHERE id <= ?1",
+                params![tx.last_insert_rowid() - MAX_STATUS_SNAPSHOTS],
+            )?;
+            tx.commit()
+        })
+    }
+
+    pub fn get_top_artists(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ArtistStats>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let mut stmt = self.conn.prepare(
             "SELECT
                 COALESCE(album_artist, artist) AS artist_name,
                 COUNT(*) AS play_count,
                 ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
             FROM plays
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
             GROUP BY artist_name
-            ORDER BY total_minutes DESC".to_string()
-        };
+            ORDER BY total_minutes DESC
+            LIMIT ?3",
+        )?;
+
+        let artists = stmt
+            .query_map(params![cutoff, source, sql_limit(limit)], |row| {
+                Ok(ArtistStats {
+                    artist_name: row.get(0)?,
+                    play_count: row.get(1)?,
+                    total_minutes: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(artists)
+    }
 
-        let mut stmt = self.conn.prepare(&query)?;
+    /// Top artists crediting each artist of a multi-artist play separately.
+    ///
+    /// Plays recorded without splitting enabled are credited to their track artist.
+    pub fn get_top_split_artists(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ArtistStats>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
 
-        let artists = stmt.query_map([], |row| {
-            Ok(ArtistStats {
-                artist_name: row.get(0)?,
-                play_count: row.get(1)?,
-                total_minutes: row.get(2)?,
-            })
-        })?
+        let mut stmt = self.conn.prepare(
+            "SELECT
+                COALESCE(pa.artist, p.artist, p.album_artist) AS artist_name,
+                COUNT(*) AS play_count,
+                ROUND(SUM(p.song_duration_seconds) / 60.0, 2) AS total_minutes
+            FROM plays p
+            LEFT JOIN plays_artists pa ON pa.play_id = p.id
+            WHERE p.timestamp >= ?1 AND (?2 IS NULL OR p.source = ?2)
+            GROUP BY artist_name
+            ORDER BY total_minutes DESC
+            LIMIT ?3",
+        )?;
+
+        let artists = stmt
+            .query_map(params![cutoff, source, sql_limit(limit)], |row| {
+                Ok(ArtistStats {
+                    artist_name: row.get(0)?,
+                    play_count: row.get(1)?,
+                    total_minutes: row.get(2)?,
+                })
+            })?
             .collect::<Result<Vec<_>>>()?;
 
         Ok(artists)
     }
 
-    pub fn get_top_songs(&self, interval: TimeInterval) -> Result<Vec<SongStats>> {
-        let cutoff = self.get_cutoff_timestamp(interval);
+    /// Compare two artists side by side, matching their names case-insensitively
+    pub fn compare_artists(
+        &self,
+        a: &str,
+        b: &str,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<ArtistComparison> {
+        Ok(ArtistComparison {
+            first: self.artist_standing(a, interval, source)?,
+            second: self.artist_standing(b, interval, source)?,
+        })
+    }
+
+    /// An artist's stats and rank among the top artists, as grouped by `get_top_artists`
+    fn artist_standing(
+        &self,
+        artist: &str,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<ArtistStanding> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let standing = self
+            .conn
+            .query_row(
+                "WITH ranked AS (
+                    SELECT
+                        COALESCE(album_artist, artist) AS artist_name,
+                        COUNT(*) AS play_count,
+                        ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes,
+                        MIN(timestamp) AS first_played,
+                        MAX(timestamp) AS last_played,
+                        RANK() OVER (ORDER BY SUM(song_duration_seconds) DESC) AS rank
+                    FROM plays
+                    WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
+                    GROUP BY artist_name
+                )
+                SELECT artist_name, play_count, total_minutes, first_played, last_played, rank
+                FROM ranked
+                WHERE artist_name = ?3 COLLATE NOCASE
+                ORDER BY rank
+                LIMIT 1",
+                params![cutoff, source, artist],
+                |row| {
+                    Ok(ArtistStanding {
+                        artist_name: row.get(0)?,
+                        play_count: row.get(1)?,
+                        total_minutes: row.get(2)?,
+                        first_played: row.get(3)?,
+                        last_played: row.get(4)?,
+                        rank: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(standing.unwrap_or_else(|| ArtistStanding {
+            artist_name: artist.to_string(),
+            play_count: 0,
+            total_minutes: 0.0,
+            first_played: None,
+            last_played: None,
+            rank: None,
+        }))
+    }
+
+    pub fn get_top_songs(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<SongStats>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
 
-        let query = if let Some(cutoff_ts) = cutoff {
-            format!(
-                "SELECT
+        let mut stmt = self.conn.prepare(
+            "SELECT songs.title, songs.artist_name, songs.play_count, songs.total_minutes, plays.file
+            FROM (
+                SELECT
                     title,
                     COALESCE(album_artist, artist) AS artist_name,
                     COUNT(*) AS play_count,
-                    ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
+                    ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes,
+                    MAX(CASE WHEN file IS NOT NULL THEN id END) AS file_play_id
                 FROM plays
-                WHERE timestamp >= {}
+                WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
                 GROUP BY title, artist_name
-                ORDER BY total_minutes DESC",
-                cutoff_ts
-            )
-        } else {
-            "SELECT
-                title,
-                COALESCE(album_artist, artist) AS artist_name,
-                COUNT(*) AS play_count,
-                ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
-            FROM plays
-            GROUP BY title, artist_name
-            ORDER BY total_minutes DESC".to_string()
-        };
-
-        let mut stmt = self.conn.prepare(&query)?;
+                ORDER BY total_minutes DESC
+                LIMIT ?3
+            ) AS songs
+            LEFT JOIN plays ON plays.id = songs.file_play_id
+            ORDER BY songs.total_minutes DESC",
+        )?;
 
-        let songs = stmt.query_map([], |row| {
-            Ok(SongStats {
-                title: row.get(0)?,
-                artist_name: row.get(1)?,
-                play_count: row.get(2)?,
-                total_minutes: row.get(3)?,
-            })
-        })?
+        let songs = stmt
+            .query_map(params![cutoff, source, sql_limit(limit)], |row| {
+                Ok(SongStats {
+                    title: row.get(0)?,
+                    artist_name: row.get(1)?,
+                    play_count: row.get(2)?,
+                    total_minutes: row.get(3)?,
+                    file: row.get(4)?,
+                })
+            })?
             .collect::<Result<Vec<_>>>()?;
 
         Ok(songs)
     }
 
-    pub fn get_top_albums(&self, interval: TimeInterval) -> Result<Vec<AlbumStats>> {
-        let cutoff = self.get_cutoff_timestamp(interval);
+    pub fn get_top_albums(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<AlbumStats>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
 
-        let query = if let Some(cutoff_ts) = cutoff {
-            format!(
-                "SELECT
-                    album,
-                    COALESCE(album_artist, artist) AS artist_name,
-                    COUNT(*) AS play_count,
-                    ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
-                FROM plays
-                WHERE album IS NOT NULL AND timestamp >= {}
-                GROUP BY album, artist_name
-                ORDER BY total_minutes DESC",
-                cutoff_ts
-            )
-        } else {
+        let mut stmt = self.conn.prepare(
             "SELECT
                 album,
                 COALESCE(album_artist, artist) AS artist_name,
                 COUNT(*) AS play_count,
                 ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
             FROM plays
-            WHERE album IS NOT NULL
+            WHERE album IS NOT NULL AND timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
             GROUP BY album, artist_name
-            ORDER BY total_minutes DESC".to_string()
-        };
-
-        let mut stmt = self.conn.prepare(&query)?;
+            ORDER BY total_minutes DESC
+            LIMIT ?3",
+        )?;
 
-        let albums = stmt.query_map([], |row| {
-            Ok(AlbumStats {
-                album: row.get(0)?,
-                artist_name: row.get(1)?,
-                play_count: row.get(2)?,
-                total_minutes: row.get(3)?,
-            })
-        })?
+        let albums = stmt
+            .query_map(params![cutoff, source, sql_limit(limit)], |row| {
+                Ok(AlbumStats {
+                    album: row.get(0)?,
+                    artist_name: row.get(1)?,
+                    play_count: row.get(2)?,
+                    total_minutes: row.get(3)?,
+                })
+            })?
             .collect::<Result<Vec<_>>>()?;
 
         Ok(albums)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Get every source plays were recorded from, with their play counts.
+    /// Plays recorded without a source are counted under `None`.
+    pub fn sources(&self) -> Result<Vec<(Option<String>, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT source, COUNT(*) AS play_count
+            FROM plays
+            GROUP BY source
+            ORDER BY play_count DESC",
+        )?;
 
-    #[test]
-    fn test_basic_operations() -> Result<()> {
-        let db = MusicDb::new(":memory:")?;
+        let sources = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
 
-        let record = PlayRecord {
-            timestamp: 1702800000,
-            title: Some("Test Song".to_string()),
-            artist: Some("Test Artist".to_string()),
-            album: Some("Test Album".to_string()),
-            album_artist: Some("Test Artist".to_string()),
-            date: Some("2023".to_string()),
-            other_tags: Default::default(),
-            song_duration_seconds: None,
-        };
+        Ok(sources)
+    }
 
-        let play_id = db.log_play(&record)?;
-        assert!(play_id > 0);
+    /// Call `f` with each play with `start <= timestamp < end`, oldest first, reading them one at a
+    /// time. Missing bounds are open.
+    ///
+    /// Memory use doesn't grow with the number of plays. Stops at the first error `f` returns.
+    pub fn for_each_play<E: From<rusqlite::Error>>(
+        &self,
+        start: Option<i64>,
+        end: Option<i64>,
+        mut f: impl FnMut(PlayRecord) -> std::result::Result<(), E>,
+    ) -> std::result::Result<(), E> {
+        let start = start.unwrap_or(i64::MIN);
+        let end = end.unwrap_or(i64::MAX);
 
-        let top_artists = db.top_artists(10)?;
-        assert_eq!(top_artists.len(), 1);
-        assert_eq!(top_artists[0].0, "Test Artist");
-        assert_eq!(top_artists[0].1, 1);
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {PLAY_COLUMNS} FROM plays
+             WHERE timestamp >= ?1 AND timestamp < ?2
+             ORDER BY timestamp, id"
+        ))?;
+
+        let mut rows = stmt.query(params![start, end])?;
+        while let Some(row) = rows.next()? {
+            f(self.read_play(row)?)?;
+        }
 
         Ok(())
     }
 
-    #[test]
-    fn test_multiple_plays() -> Result<()> {
-        let db = MusicDb::new(":memory:")?;
+    /// Get a single play by its id, as it was logged
+    pub fn get_play(&self, id: i64) -> Result<Option<PlayRecord>> {
+        self.conn
+            .query_row(
+                &format!("SELECT {PLAY_COLUMNS} FROM plays WHERE id = ?1"),
+                params![id],
+                |row| self.read_play(row),
+            )
+            .optional()
+    }
 
-        for i in 0..5 {
-            let record = PlayRecord {
-                timestamp: 1702800000 + i,
-                title: Some(format!("Song {}", i)),
-                artist: Some("Same Artist".to_string()),
-                album: Some("Same Album".to_string()),
-                album_artist: Some("Same Artist".to_string()),
-                date: Some("2023".to_string()),
-                other_tags: Default::default(),
-                song_duration_seconds: None,
+    /// Pick up to `n` distinct songs played in the interval at random, each as its latest play
+    pub fn random_sample(
+        &self,
+        n: usize,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<Vec<PlayRecord>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {PLAY_COLUMNS} FROM plays
+             WHERE id IN (
+                 SELECT MAX(id) FROM plays
+                 WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2) AND title IS NOT NULL
+                 GROUP BY title, artist
+             )
+             ORDER BY RANDOM()
+             LIMIT ?3"
+        ))?;
+
+        let mut rows = stmt.query(params![cutoff, source, n as i64])?;
+        let mut plays = Vec::new();
+        while let Some(row) = rows.next()? {
+            plays.push(self.read_play(row)?);
+        }
+
+        Ok(plays)
+    }
+
+    /// Build a play from a row of [`PLAY_COLUMNS`], reading its other tags
+    fn read_play(&self, row: &rusqlite::Row) -> Result<PlayRecord> {
+        let mut tags_stmt = self.conn.prepare_cached(
+            "SELECT tag_name, tag_value FROM plays_other_tags WHERE play_id = ?1 ORDER BY rowid",
+        )?;
+        let mut other_tags: HashMap<String, Vec<String>> = HashMap::new();
+        let mut tag_rows = tags_stmt.query(params![row.get::<_, i64>(0)?])?;
+        while let Some(tag) = tag_rows.next()? {
+            other_tags.entry(tag.get(0)?).or_default().push(tag.get(1)?);
+        }
+
+        Ok(PlayRecord {
+            timestamp: row.get(1)?,
+            title: row.get(2)?,
+            artist: row.get(3)?,
+            album: row.get(4)?,
+            album_artist: row.get(5)?,
+            date: row.get(6)?,
+            other_tags,
+            song_duration_seconds: row.get(7)?,
+            source: row.get(8)?,
+            random: row.get(9)?,
+            file: row.get(10)?,
+        })
+    }
+
+    /// Re-extract every play from its stored tags with the current rules, i.e. `filter` and the
+    /// artist separators, returning how many plays changed.
+    ///
+    /// The names stored with a play are what extraction produced, so in practice this drops the
+    /// other tags `filter` now drops, for good, and re-splits artists. Runs in a single
+    /// transaction, which `dry_run` rolls back to only count the changes.
+    pub fn reprocess_plays(&self, filter: &TagFilter, dry_run: bool) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+
+        let ids = self
+            .conn
+            .prepare("SELECT id FROM plays ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<i64>>>()?;
+
+        let mut changed = 0;
+        for id in ids {
+            let Some(play) = self.get_play(id)? else {
+                continue;
             };
-            db.log_play(&record)?;
+            let updated = play.clone().reextract(filter);
+
+            // only redo the split artists if splitting is on, they're unused otherwise
+            let artists_changed = !self.artist_separators.is_empty() && {
+                let split = updated
+                    .artist
+                    .as_deref()
+                    .map(|artist| split_artists(artist, &self.artist_separators))
+                    .unwrap_or_default();
+                let stored = self
+                    .conn
+                    .prepare_cached(
+                        "SELECT artist FROM plays_artists WHERE play_id = ?1 ORDER BY rowid",
+                    )?
+                    .query_map(params![id], |row| row.get(0))?
+                    .collect::<Result<Vec<String>>>()?;
+                let differs = split != stored;
+                if differs {
+                    self.conn
+                        .execute("DELETE FROM plays_artists WHERE play_id = ?1", params![id])?;
+                    for artist in split {
+                        self.conn.execute(
+                            "INSERT INTO plays_artists (play_id, artist) VALUES (?1, ?2)",
+                            params![id, artist],
+                        )?;
+                    }
+                }
+                differs
+            };
+
+            if updated == play && !artists_changed {
+                continue;
+            }
+            changed += 1;
+
+            self.conn.execute(
+                "UPDATE plays SET title = ?2, artist = ?3, album = ?4, album_artist = ?5, date = ?6
+                 WHERE id = ?1",
+                params![
+                    id,
+                    updated.title.as_deref().filter(|title| !title.is_empty()),
+                    updated
+                        .artist
+                        .as_deref()
+                        .filter(|artist| !artist.is_empty()),
+                    updated.album,
+                    updated.album_artist,
+                    updated.date
+                ],
+            )?;
+            if updated.other_tags != play.other_tags {
+                self.conn.execute(
+                    "DELETE FROM plays_other_tags WHERE play_id = ?1",
+                    params![id],
+                )?;
+                for (tag_name, tag_values) in &updated.other_tags {
+                    for tag_value in tag_values {
+                        self.conn.execute(
+                            "INSERT INTO plays_other_tags (play_id, tag_name, tag_value) VALUES (?1, ?2, ?3)",
+                            params![id, tag_name, tag_value],
+                        )?;
+                    }
+                }
+            }
         }
 
-        let top_artists = db.top_artists(10)?;
-        assert_eq!(top_artists[0].1, 5);
+        if dry_run {
+            tx.rollback()?;
+        } else {
+            tx.commit()?;
+        }
+        Ok(changed)
+    }
+
+    /// The fraction of the last `months` calendar months, this one included, in which each artist
+    /// was played, most consistent first. Ties go to the artist listened to the most.
+    pub fn artist_consistency(
+        &self,
+        months: u32,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, f64)>> {
+        let months = months.max(1);
+
+        let mut stmt = self.conn.prepare(
+            "WITH recent AS (
+                SELECT
+                    COALESCE(album_artist, artist) AS artist_name,
+                    substr(local_date(timestamp), 1, 7) AS month,
+                    song_duration_seconds
+                FROM plays
+                WHERE local_date(timestamp) >= date(
+                        local_date(CAST(strftime('%s', 'now') AS INTEGER)), 'start of month', ?1
+                    )
+                    AND (?2 IS NULL OR source = ?2)
+            )
+            SELECT artist_name, COUNT(DISTINCT month) * 1.0 / ?4 AS consistency
+            FROM recent
+            WHERE artist_name IS NOT NULL
+            GROUP BY artist_name
+            ORDER BY consistency DESC, SUM(song_duration_seconds) DESC, artist_name
+            LIMIT ?3",
+        )?;
+
+        let start = format!("-{} months", months - 1);
+        let artists = stmt
+            .query_map(params![start, source, sql_limit(limit), months], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(artists)
+    }
+
+    /// Which of the `top_n` all-time top artists weren't played at all in the interval, with their
+    /// all-time play counts, in all-time order
+    pub fn absent_favorites(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        top_n: Option<usize>,
+    ) -> Result<Vec<(String, i64)>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let mut stmt = self.conn.prepare(
+            "WITH favorites AS (
+                SELECT
+                    COALESCE(album_artist, artist) AS artist_name,
+                    COUNT(*) AS play_count,
+                    SUM(song_duration_seconds) AS total_seconds,
+                    MAX(timestamp) AS last_played
+                FROM plays
+                WHERE ?2 IS NULL OR source = ?2
+                GROUP BY artist_name
+                HAVING artist_name IS NOT NULL
+                ORDER BY total_seconds DESC
+                LIMIT ?3
+            )
+            SELECT artist_name, play_count
+            FROM favorites
+            WHERE last_played < ?1
+            ORDER BY total_seconds DESC",
+        )?;
+
+        let artists = stmt
+            .query_map(params![cutoff, source, sql_limit(top_n)], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(artists)
+    }
+
+    /// How many different albums of each artist were played, as `(artist, albums, plays)`, most
+    /// albums first
+    pub fn albums_per_artist(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let mut stmt = self.conn.prepare(
+            "SELECT
+                COALESCE(album_artist, artist) AS artist_name,
+                COUNT(DISTINCT album) AS album_count,
+                COUNT(*) AS play_count
+            FROM plays
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
+            GROUP BY artist_name
+            HAVING artist_name IS NOT NULL
+            ORDER BY album_count DESC, play_count DESC, artist_name
+            LIMIT ?3",
+        )?;
+
+        let artists = stmt
+            .query_map(params![cutoff, source, sql_limit(limit)], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(artists)
+    }
+
+    /// Days with the most minutes listened, as `(YYYY-MM-DD, minutes, plays)`
+    pub fn top_listening_days(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, f64, i64)>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let mut stmt = self.conn.prepare(
+            "SELECT
+                local_date(timestamp) AS day,
+                ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes,
+                COUNT(*) AS play_count
+            FROM plays
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
+            GROUP BY day
+            ORDER BY total_minutes DESC, day
+            LIMIT ?3",
+        )?;
+
+        let days = stmt
+            .query_map(params![cutoff, source, sql_limit(limit)], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(days)
+    }
+
+    /// Minutes listened by the release year in the `date` tag, oldest first.
+    ///
+    /// Plays whose date doesn't start with a plausible year (missing, garbage, or after next year)
+    /// are counted under `None`, which comes last.
+    pub fn minutes_by_release_year(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<Vec<(Option<i32>, f64)>> {
+        const EARLIEST_YEAR: i32 = 1000;
+
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+        let latest_year = chrono::Datelike::year(&chrono::Local::now()) + 1;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT
+                CASE
+                    WHEN substr(date, 1, 4) GLOB '[0-9][0-9][0-9][0-9]'
+                        AND CAST(substr(date, 1, 4) AS INTEGER) BETWEEN ?3 AND ?4
+                    THEN CAST(substr(date, 1, 4) AS INTEGER)
+                END AS year,
+                ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
+            FROM plays
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
+            GROUP BY year
+            ORDER BY year IS NULL, year",
+        )?;
+
+        let years = stmt
+            .query_map(params![cutoff, source, EARLIEST_YEAR, latest_year], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(years)
+    }
+
+    /// Minutes listened with random mode on and with it off, in that order. Plays recorded before
+    /// random mode was tracked are left out.
+    pub fn minutes_by_random_mode(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<(f64, f64)> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        self.conn.query_row(
+            "SELECT
+                COALESCE(ROUND(SUM(CASE WHEN random THEN song_duration_seconds END) / 60.0, 2), 0.0),
+                COALESCE(ROUND(SUM(CASE WHEN NOT random THEN song_duration_seconds END) / 60.0, 2), 0.0)
+            FROM plays
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2) AND random IS NOT NULL",
+            params![cutoff, source],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    /// Plays, minutes and active calendar days in the interval, and when it effectively started
+    pub fn activity(&self, interval: TimeInterval, source: Option<&str>) -> Result<ActivityStats> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        self.conn.query_row(
+            "SELECT
+                COUNT(*),
+                COALESCE(ROUND(SUM(song_duration_seconds) / 60.0, 2), 0.0),
+                COUNT(DISTINCT local_date(timestamp)),
+                MAX(?1, (SELECT MIN(timestamp) FROM plays WHERE ?2 IS NULL OR source = ?2))
+            FROM plays
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)",
+            params![cutoff, source],
+            |row| {
+                Ok(ActivityStats {
+                    play_count: row.get(0)?,
+                    total_minutes: row.get(1)?,
+                    active_days: row.get(2)?,
+                    since: row.get(3)?,
+                })
+            },
+        )
+    }
+
+    /// Plays and minutes on weekdays and on weekends, in that order
+    pub fn weekday_weekend_split(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<(WeekdayStats, WeekdayStats)> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let mut stmt = self.conn.prepare(
+            "SELECT
+                strftime('%w', local_date(timestamp)) IN ('0', '6') AS weekend,
+                COUNT(*) AS play_count,
+                ROUND(SUM(song_duration_seconds) / 60.0, 2) AS total_minutes
+            FROM plays
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)
+            GROUP BY weekend",
+        )?;
+
+        let mut weekdays = WeekdayStats::default();
+        let mut weekends = WeekdayStats::default();
+        let rows = stmt.query_map(params![cutoff, source], |row| {
+            Ok((row.get::<_, bool>(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        for row in rows {
+            let (weekend, play_count, total_minutes) = row?;
+            let stats = if weekend {
+                &mut weekends
+            } else {
+                &mut weekdays
+            };
+            *stats = WeekdayStats {
+                play_count,
+                total_minutes,
+            };
+        }
+
+        Ok((weekdays, weekends))
+    }
+
+    /// Get, for each month with plays, the number of distinct artists heard up to and including it
+    pub fn cumulative_distinct_artists_by_month(
+        &self,
+        source: Option<&str>,
+    ) -> Result<Vec<(String, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT
+                substr(local_date(timestamp), 1, 7) AS month,
+                COALESCE(album_artist, artist) AS artist_name
+            FROM plays
+            WHERE artist_name IS NOT NULL AND (?1 IS NULL OR source = ?1)
+            ORDER BY month",
+        )?;
+
+        let pairs = stmt
+            .query_map(params![source], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut seen = HashSet::new();
+        let mut growth: Vec<(String, i64)> = Vec::new();
+        for (month, artist) in pairs {
+            seen.insert(artist);
+            match growth.last_mut() {
+                Some((last_month, count)) if *last_month == month => *count = seen.len() as i64,
+                _ => growth.push((month, seen.len() as i64)),
+            }
+        }
+
+        Ok(growth)
+    }
+
+    /// Get top genres by minutes listened. Plays without a Genre tag fall back to their derived genre.
+    pub fn get_top_genres(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<GenreStats>> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let mut stmt = self.conn.prepare(
+            "SELECT
+                COALESCE(t.genre, p.derived_genre) AS genre_name,
+                t.genre IS NULL AS inferred,
+                COUNT(*) AS play_count,
+                ROUND(SUM(p.song_duration_seconds) / 60.0, 2) AS total_minutes
+            FROM plays p
+            LEFT JOIN (
+                SELECT play_id, MIN(tag_value) AS genre
+                FROM plays_other_tags
+                WHERE tag_name = 'Genre'
+                GROUP BY play_id
+            ) t ON t.play_id = p.id
+            WHERE genre_name IS NOT NULL AND p.timestamp >= ?1 AND (?2 IS NULL OR p.source = ?2)
+            GROUP BY genre_name, inferred
+            ORDER BY total_minutes DESC
+            LIMIT ?3",
+        )?;
+
+        let genres = stmt
+            .query_map(params![cutoff, source, sql_limit(limit)], |row| {
+                Ok(GenreStats {
+                    genre: row.get(0)?,
+                    inferred: row.get(1)?,
+                    play_count: row.get(2)?,
+                    total_minutes: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(genres)
+    }
+
+    /// Get artists with plays that have neither a Genre tag nor a derived genre
+    pub fn artists_missing_genre(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT artist
+            FROM plays p
+            WHERE artist IS NOT NULL
+                AND derived_genre IS NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM plays_other_tags t WHERE t.play_id = p.id AND t.tag_name = 'Genre'
+                )
+            ORDER BY artist",
+        )?;
+
+        let artists = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(artists)
+    }
+
+    /// Get the cached genre lookup for an artist. `None` if the artist was never looked up,
+    /// `Some(None)` if it was but no genre was found.
+    pub fn cached_artist_genre(&self, artist: &str) -> Result<Option<Option<String>>> {
+        self.conn
+            .query_row(
+                "SELECT genre FROM artist_genres WHERE artist = ?1",
+                params![artist],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Cache the result of a genre lookup for an artist
+    pub fn cache_artist_genre(&self, artist: &str, genre: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO artist_genres (artist, genre, fetched_at)
+             VALUES (?1, ?2, strftime('%s', 'now'))
+             ON CONFLICT (artist) DO UPDATE SET genre = excluded.genre, fetched_at = excluded.fetched_at",
+            params![artist, genre],
+        )?;
+        Ok(())
+    }
+
+    /// Set the derived genre on an artist's plays that have no Genre tag, returning how many changed
+    pub fn set_derived_genre(&self, artist: &str, genre: &str) -> Result<usize> {
+        self.conn.execute(
+            "UPDATE plays
+            SET derived_genre = ?2
+            WHERE artist = ?1
+                AND derived_genre IS NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM plays_other_tags t WHERE t.play_id = plays.id AND t.tag_name = 'Genre'
+                )",
+            params![artist, genre],
+        )
+    }
+
+    /// Get the fraction of plays in the interval that were the first ever play of that song
+    pub fn novelty_ratio(&self, interval: TimeInterval, source: Option<&str>) -> Result<f64> {
+        let cutoff = self.get_cutoff_timestamp(interval).unwrap_or(0);
+
+        let (play_count, first_play_count): (i64, i64) = self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(play_number = 1), 0)
+            FROM (
+                SELECT
+                    timestamp,
+                    source,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY title, COALESCE(album_artist, artist)
+                        ORDER BY timestamp, id
+                    ) AS play_number
+                FROM plays
+            )
+            WHERE timestamp >= ?1 AND (?2 IS NULL OR source = ?2)",
+            params![cutoff, source],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        if play_count == 0 {
+            return Ok(0.0);
+        }
+
+        Ok(first_play_count as f64 / play_count as f64)
+    }
+}
+
+impl PlayStore for MusicDb {
+    fn log_play(&self, record: &PlayRecord) -> crate::error::Result<i64> {
+        Ok(MusicDb::log_play(self, record)?)
+    }
+
+    fn record_reaction(&self, record: &ReactionRecord) -> crate::error::Result<()> {
+        Ok(MusicDb::record_reaction(self, record)?)
+    }
+
+    fn loved_tracks(&self) -> crate::error::Result<Vec<PlayRecord>> {
+        Ok(MusicDb::loved_tracks(self)?)
+    }
+
+    fn log_queued(&self, record: &QueuedRecord) -> crate::error::Result<i64> {
+        Ok(MusicDb::log_queued(self, record)?)
+    }
+
+    fn reprocess_plays(&self, filter: &TagFilter, dry_run: bool) -> crate::error::Result<usize> {
+        Ok(MusicDb::reprocess_plays(self, filter, dry_run)?)
+    }
+
+    fn log_plays(&self, records: &[PlayRecord]) -> crate::error::Result<()> {
+        Ok(MusicDb::log_plays(self, records)?)
+    }
+
+    fn for_each_play(
+        &self,
+        start: Option<i64>,
+        end: Option<i64>,
+        f: &mut dyn FnMut(PlayRecord) -> crate::error::Result<()>,
+    ) -> crate::error::Result<()> {
+        MusicDb::for_each_play(self, start, end, f)
+    }
+
+    fn random_sample(
+        &self,
+        n: usize,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> crate::error::Result<Vec<PlayRecord>> {
+        Ok(MusicDb::random_sample(self, n, interval, source)?)
+    }
+
+    fn get_top_artists(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<ArtistStats>> {
+        Ok(MusicDb::get_top_artists(self, interval, source, limit)?)
+    }
+
+    fn get_top_songs(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<SongStats>> {
+        Ok(MusicDb::get_top_songs(self, interval, source, limit)?)
+    }
+
+    fn get_top_split_artists(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<ArtistStats>> {
+        Ok(MusicDb::get_top_split_artists(
+            self, interval, source, limit,
+        )?)
+    }
+
+    fn compare_artists(
+        &self,
+        a: &str,
+        b: &str,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> crate::error::Result<ArtistComparison> {
+        Ok(MusicDb::compare_artists(self, a, b, interval, source)?)
+    }
+
+    fn get_top_albums(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<AlbumStats>> {
+        Ok(MusicDb::get_top_albums(self, interval, source, limit)?)
+    }
+
+    fn get_top_genres(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<GenreStats>> {
+        Ok(MusicDb::get_top_genres(self, interval, source, limit)?)
+    }
+
+    fn artists_missing_genre(&self) -> crate::error::Result<Vec<String>> {
+        Ok(MusicDb::artists_missing_genre(self)?)
+    }
+
+    fn cached_artist_genre(&self, artist: &str) -> crate::error::Result<Option<Option<String>>> {
+        Ok(MusicDb::cached_artist_genre(self, artist)?)
+    }
+
+    fn cache_artist_genre(&self, artist: &str, genre: Option<&str>) -> crate::error::Result<()> {
+        Ok(MusicDb::cache_artist_genre(self, artist, genre)?)
+    }
+
+    fn set_derived_genre(&self, artist: &str, genre: &str) -> crate::error::Result<usize> {
+        Ok(MusicDb::set_derived_genre(self, artist, genre)?)
+    }
+
+    fn novelty_ratio(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> crate::error::Result<f64> {
+        Ok(MusicDb::novelty_ratio(self, interval, source)?)
+    }
+
+    fn sources(&self) -> crate::error::Result<Vec<(Option<String>, i64)>> {
+        Ok(MusicDb::sources(self)?)
+    }
+
+    fn artist_consistency(
+        &self,
+        months: u32,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<(String, f64)>> {
+        Ok(MusicDb::artist_consistency(self, months, source, limit)?)
+    }
+
+    fn absent_favorites(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        top_n: Option<usize>,
+    ) -> crate::error::Result<Vec<(String, i64)>> {
+        Ok(MusicDb::absent_favorites(self, interval, source, top_n)?)
+    }
+
+    fn albums_per_artist(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<(String, i64, i64)>> {
+        Ok(MusicDb::albums_per_artist(self, interval, source, limit)?)
+    }
+
+    fn top_listening_days(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> crate::error::Result<Vec<(String, f64, i64)>> {
+        Ok(MusicDb::top_listening_days(self, interval, source, limit)?)
+    }
+
+    fn minutes_by_release_year(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> crate::error::Result<Vec<(Option<i32>, f64)>> {
+        Ok(MusicDb::minutes_by_release_year(self, interval, source)?)
+    }
+
+    fn minutes_by_random_mode(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> crate::error::Result<(f64, f64)> {
+        Ok(MusicDb::minutes_by_random_mode(self, interval, source)?)
+    }
+
+    fn activity(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> crate::error::Result<ActivityStats> {
+        Ok(MusicDb::activity(self, interval, source)?)
+    }
+
+    fn weekday_weekend_split(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> crate::error::Result<(WeekdayStats, WeekdayStats)> {
+        Ok(MusicDb::weekday_weekend_split(self, interval, source)?)
+    }
+
+    fn cumulative_distinct_artists_by_month(
+        &self,
+        source: Option<&str>,
+    ) -> crate::error::Result<Vec<(String, i64)>> {
+        Ok(MusicDb::cumulative_distinct_artists_by_month(self, source)?)
+    }
+
+    fn last_query_timestamp(&self) -> crate::error::Result<Option<i64>> {
+        Ok(MusicDb::last_query_timestamp(self)?)
+    }
+
+    fn set_last_query_timestamp(&self, timestamp: i64) -> crate::error::Result<()> {
+        Ok(MusicDb::set_last_query_timestamp(self, timestamp)?)
+    }
+
+    fn playcount_baseline(
+        &self,
+        source: Option<&str>,
+    ) -> crate::error::Result<Option<(i64, HashMap<String, u64>)>> {
+        Ok(MusicDb::playcount_baseline(self, source)?)
+    }
+
+    fn record_catch_up(
+        &self,
+        source: Option<&str>,
+        taken_at: i64,
+        counts: &[(String, u64)],
+        backfilled: &[PlayRecord],
+    ) -> crate::error::Result<()> {
+        Ok(MusicDb::record_catch_up(
+            self, source, taken_at, counts, backfilled,
+        )?)
+    }
+
+    fn plays_by_file_since(
+        &self,
+        source: Option<&str>,
+        since: i64,
+    ) -> crate::error::Result<HashMap<String, u64>> {
+        Ok(MusicDb::plays_by_file_since(self, source, since)?)
+    }
+
+    fn start_session(
+        &self,
+        source: Option<&str>,
+        server_version: Option<&str>,
+        timestamp: i64,
+    ) -> crate::error::Result<i64> {
+        Ok(MusicDb::start_session(
+            self,
+            source,
+            server_version,
+            timestamp,
+        )?)
+    }
+
+    fn record_heartbeat(&self, session: i64, timestamp: i64) -> crate::error::Result<()> {
+        Ok(MusicDb::record_heartbeat(self, session, timestamp)?)
+    }
+
+    fn record_status_snapshot(
+        &self,
+        session: i64,
+        timestamp: i64,
+        status: &SongStatus,
+    ) -> crate::error::Result<()> {
+        Ok(MusicDb::record_status_snapshot(
+            self, session, timestamp, status,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use crate::persistence::Reaction;
+    use chrono::Datelike;
+
+    fn get_plays(db: &MusicDb, start: Option<i64>, end: Option<i64>) -> Result<Vec<PlayRecord>> {
+        let mut plays = Vec::new();
+        db.for_each_play(start, end, |play| {
+            plays.push(play);
+            Ok::<_, rusqlite::Error>(())
+        })?;
+        Ok(plays)
+    }
+
+    /// A play of "Song" by "Artist" at `timestamp` with nothing else set, for tests to override
+    /// what they need with struct update syntax
+    fn play(timestamp: i64) -> PlayRecord {
+        PlayRecord {
+            timestamp,
+            title: Some("Song".to_string()),
+            artist: Some("Artist".to_string()),
+            album: None,
+            album_artist: None,
+            date: None,
+            other_tags: Default::default(),
+            song_duration_seconds: None,
+            source: None,
+            random: None,
+            file: None,
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_basic_operations() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        let record = PlayRecord {
+            timestamp: 1702800000,
+            title: Some("Test Song".to_string()),
+            artist: Some("Test Artist".to_string()),
+            album: Some("Test Album".to_string()),
+            album_artist: Some("Test Artist".to_string()),
+            date: Some("2023".to_string()),
+            other_tags: Default::default(),
+            song_duration_seconds: None,
+            source: None,
+            random: None,
+            file: None,
+        };
+
+        let play_id = db.log_play(&record)?;
+        assert!(play_id > 0);
+
+        let top_artists = db.top_artists(10)?;
+        assert_eq!(top_artists.len(), 1);
+        assert_eq!(top_artists[0].0, "Test Artist");
+        assert_eq!(top_artists[0].1, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inserted_at() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let play_id = db.log_play(&play(1702800000))?;
+
+        // the listening time is kept, the insertion time is the current time
+        let (timestamp, inserted_at): (i64, i64) = db.conn.query_row(
+            "SELECT timestamp, inserted_at FROM plays WHERE id = ?1",
+            params![play_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!(timestamp, 1702800000);
+        assert!((inserted_at - chrono::Utc::now().timestamp()).abs() < 60);
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_multiple_plays() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for i in 0..5 {
+            let record = PlayRecord {
+                timestamp: 1702800000 + i,
+                title: Some(format!("Song {}", i)),
+                artist: Some("Same Artist".to_string()),
+                album: Some("Same Album".to_string()),
+                album_artist: Some("Same Artist".to_string()),
+                date: Some("2023".to_string()),
+                other_tags: Default::default(),
+                song_duration_seconds: None,
+                source: None,
+                random: None,
+                file: None,
+            };
+            db.log_play(&record)?;
+        }
+
+        let top_artists = db.top_artists(10)?;
+        assert_eq!(top_artists[0].1, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_implausible_durations() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for duration in [
+            Some(180),
+            Some(0),
+            None,
+            Some(10_u64.pow(12)),
+            Some(u64::MAX),
+        ] {
+            db.log_play(&PlayRecord {
+                song_duration_seconds: duration,
+                ..play(1702800000)
+            })?;
+        }
+
+        let artists = db.get_top_artists(TimeInterval::AllTime, None, None)?;
+        assert_eq!(artists[0].play_count, 5);
+        assert_eq!(artists[0].total_minutes, 3.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_filter() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for (artist, source) in [
+            ("A", Some("living-room")),
+            ("A", Some("office")),
+            ("B", None),
+        ] {
+            db.log_play(&PlayRecord {
+                artist: Some(artist.to_string()),
+                album: Some("Album".to_string()),
+                song_duration_seconds: Some(180),
+                source: source.map(str::to_string),
+                ..play(1702800000)
+            })?;
+        }
+
+        let artists = db.get_top_artists(TimeInterval::AllTime, None, None)?;
+        assert_eq!(artists.len(), 2);
+
+        let artists = db.get_top_artists(TimeInterval::AllTime, Some("office"), None)?;
+        assert_eq!(artists.len(), 1);
+        assert_eq!(artists[0].artist_name, "A");
+        assert_eq!(artists[0].play_count, 1);
+
+        assert!(db
+            .get_top_songs(TimeInterval::AllTime, Some("garage"), None)?
+            .is_empty());
+        assert_eq!(
+            db.get_top_albums(TimeInterval::AllTime, Some("living-room"), None)?
+                .len(),
+            1
+        );
+
+        let mut sources = db.sources()?;
+        sources.sort();
+        assert_eq!(
+            sources,
+            vec![
+                (None, 1),
+                (Some("living-room".to_string()), 1),
+                (Some("office".to_string()), 1),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_limit() -> Result<()> {
+        let db = MusicDb::new(":memory:")?.with_artist_separators(vec!["&".to_string()]);
+
+        // most plays isn't most minutes: Long has 10 minutes, Mid & Guest 6 and Many 3
+        for (i, (artist, genre, seconds)) in [
+            ("Many", "Pop", 60),
+            ("Many", "Pop", 60),
+            ("Many", "Pop", 60),
+            ("Long", "Jazz", 600),
+            ("Mid & Guest", "Rock", 180),
+            ("Mid & Guest", "Rock", 180),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            db.log_play(&PlayRecord {
+                title: Some(format!("{artist} Song")),
+                artist: Some(artist.to_string()),
+                album: Some(format!("{artist} Album")),
+                other_tags: HashMap::from([("Genre".to_string(), vec![genre.to_string()])]),
+                song_duration_seconds: Some(seconds),
+                ..play(1702800000 + i as i64)
+            })?;
+        }
+
+        let interval = TimeInterval::AllTime;
+        let artists: Vec<_> = db
+            .get_top_artists(interval, None, Some(2))?
+            .into_iter()
+            .map(|artist| artist.artist_name)
+            .collect();
+        assert_eq!(artists, vec!["Long", "Mid & Guest"]);
+        assert_eq!(db.get_top_artists(interval, None, None)?.len(), 3);
+
+        let songs = db.get_top_songs(interval, None, Some(1))?;
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].title, "Long Song");
+        assert_eq!(db.get_top_songs(interval, None, None)?.len(), 3);
+
+        let albums = db.get_top_albums(interval, None, Some(2))?;
+        assert_eq!(albums.len(), 2);
+        assert_eq!(albums[1].album, "Mid & Guest Album");
+        assert_eq!(db.get_top_albums(interval, None, None)?.len(), 3);
+
+        // Mid and Guest both get the 6 minutes, Many is cut
+        let mut split: Vec<_> = db
+            .get_top_split_artists(interval, None, Some(3))?
+            .into_iter()
+            .map(|artist| artist.artist_name)
+            .collect();
+        split.sort();
+        assert_eq!(split, vec!["Guest", "Long", "Mid"]);
+        assert_eq!(db.get_top_split_artists(interval, None, None)?.len(), 4);
+
+        let genres = db.get_top_genres(interval, None, Some(1))?;
+        assert_eq!(genres.len(), 1);
+        assert_eq!(genres[0].genre, "Jazz");
+        assert_eq!(db.get_top_genres(interval, None, None)?.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_artists() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let record = |artist: &str| PlayRecord {
+            artist: Some(artist.to_string()),
+            song_duration_seconds: Some(180),
+            ..play(1702800000)
+        };
+
+        // recorded before splitting was enabled
+        db.log_play(&record("A feat. B"))?;
+
+        let db = db.with_artist_separators(vec!["feat.".to_string(), "&".to_string()]);
+        db.log_play(&record("A feat. C & D"))?;
+        db.log_play(&record("C"))?;
+
+        let artists: Vec<_> = db
+            .get_top_split_artists(TimeInterval::AllTime, None, None)?
+            .into_iter()
+            .map(|artist| (artist.artist_name, artist.play_count))
+            .collect();
+        assert_eq!(
+            artists.iter().find(|(name, _)| name == "C"),
+            Some(&("C".to_string(), 2))
+        );
+        assert_eq!(artists.len(), 4);
+
+        // the primary credit is unchanged
+        assert_eq!(
+            db.get_top_artists(TimeInterval::AllTime, None, None)?.len(),
+            3
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_artists() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for (timestamp, artist, duration) in [
+            (1702800000, "Vulfpeck", 180),
+            (1702900000, "Vulfpeck", 180),
+            (1702850000, "Khruangbin", 300),
+            (1702860000, "Cory Wong", 600),
+        ] {
+            db.log_play(&PlayRecord {
+                artist: Some(artist.to_string()),
+                song_duration_seconds: Some(duration),
+                ..play(timestamp)
+            })?;
+        }
+
+        let comparison = db.compare_artists("vulfpeck", "Nobody", TimeInterval::AllTime, None)?;
+
+        let first = comparison.first;
+        assert_eq!(first.artist_name, "Vulfpeck");
+        assert_eq!(first.play_count, 2);
+        assert_eq!(first.total_minutes, 6.0);
+        assert_eq!(first.first_played, Some(1702800000));
+        assert_eq!(first.last_played, Some(1702900000));
+        assert_eq!(first.rank, Some(2));
+
+        let second = comparison.second;
+        assert_eq!(second.artist_name, "Nobody");
+        assert_eq!(second.play_count, 0);
+        assert_eq!(second.rank, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_novelty_ratio() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for (timestamp, title) in [(1702800000, "A"), (1702800300, "A"), (1702800600, "B")] {
+            db.log_play(&PlayRecord {
+                title: Some(title.to_string()),
+                artist: Some("Same Artist".to_string()),
+                ..play(timestamp)
+            })?;
+        }
+
+        assert_eq!(db.novelty_ratio(TimeInterval::AllTime, None)?, 2.0 / 3.0);
+        // the second play of A is a repeat even though the first is outside the interval
+        assert_eq!(
+            db.novelty_ratio(TimeInterval::Since(1702800300), None)?,
+            0.5
+        );
+        assert_eq!(
+            db.novelty_ratio(TimeInterval::Since(1702900000), None)?,
+            0.0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_each_play_bounds() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for i in 0..5 {
+            db.log_play(&PlayRecord {
+                title: Some(format!("Song {}", i)),
+                artist: Some("Same Artist".to_string()),
+                other_tags: HashMap::from([("Genre".to_string(), vec!["Rock".to_string()])]),
+                song_duration_seconds: Some(180),
+                ..play(1702800000 + i * 100)
+            })?;
+        }
+
+        assert_eq!(get_plays(&db, None, None)?.len(), 5);
+
+        let plays = get_plays(&db, Some(1702800100), Some(1702800300))?;
+        assert_eq!(plays.len(), 2);
+        assert_eq!(plays[0].title.as_deref(), Some("Song 1"));
+        assert_eq!(plays[1].title.as_deref(), Some("Song 2"));
+        assert_eq!(plays[0].other_tags["Genre"], vec!["Rock".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_minutes_by_random_mode() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for random in [Some(true), Some(true), Some(false), None] {
+            db.log_play(&PlayRecord {
+                song_duration_seconds: Some(180),
+                random,
+                ..play(1702800000)
+            })?;
+        }
+
+        assert_eq!(
+            db.minutes_by_random_mode(TimeInterval::AllTime, None)?,
+            (6.0, 3.0)
+        );
+        assert_eq!(get_plays(&db, None, None)?[0].random, Some(true));
+        assert_eq!(
+            db.minutes_by_random_mode(TimeInterval::AllTime, Some("elsewhere"))?,
+            (0.0, 0.0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_artist_consistency() -> Result<()> {
+        let db = MusicDb::new(":memory:")?.with_timezone(chrono_tz::UTC)?;
+        let this_month = chrono::Utc::now().date_naive().with_day(1).unwrap();
+        let months_ago = |months: u32| {
+            (this_month - chrono::Months::new(months))
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+                .and_utc()
+                .timestamp()
+        };
+
+        // a regular every month, a binge this month, and a band from long ago
+        let mut plays: Vec<_> = (0..3).map(|m| ("Regular", months_ago(m))).collect();
+        plays.extend((0..20).map(|i| ("Binge", months_ago(0) + i)));
+        plays.push(("Old Favorite", months_ago(5)));
+
+        for (artist, timestamp) in plays {
+            db.log_play(&PlayRecord {
+                artist: Some(artist.to_string()),
+                song_duration_seconds: Some(180),
+                ..play(timestamp)
+            })?;
+        }
+
+        let consistency = db.artist_consistency(3, None, None)?;
+        assert_eq!(
+            consistency,
+            vec![
+                ("Regular".to_string(), 1.0),
+                ("Binge".to_string(), 1.0 / 3.0)
+            ]
+        );
+        assert_eq!(db.artist_consistency(6, None, Some(1))?.len(), 1);
+        assert_eq!(db.artist_consistency(6, None, None)?.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_absent_favorites() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let now = chrono::Utc::now().timestamp();
+        let long_ago = now - 365 * 24 * 60 * 60;
+
+        // A and B are favorites from long ago, only B was played this week; C is a minor artist
+        let plays = [
+            ("A", long_ago, 600),
+            ("A", long_ago, 600),
+            ("B", long_ago, 900),
+            ("B", now - 60, 60),
+            ("C", long_ago, 60),
+        ];
+        for (artist, timestamp, duration) in plays {
+            db.log_play(&PlayRecord {
+                artist: Some(artist.to_string()),
+                song_duration_seconds: Some(duration),
+                ..play(timestamp)
+            })?;
+        }
+
+        assert_eq!(
+            db.absent_favorites(TimeInterval::Week, None, Some(2))?,
+            vec![("A".to_string(), 2)]
+        );
+        assert_eq!(
+            db.absent_favorites(TimeInterval::Week, None, None)?,
+            vec![("A".to_string(), 2), ("C".to_string(), 1)]
+        );
+        assert!(db
+            .absent_favorites(TimeInterval::AllTime, None, None)?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_albums_per_artist() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        let plays = [
+            (Some("Artist"), None, Some("First")),
+            (Some("Artist"), None, Some("Second")),
+            (Some("Artist"), None, Some("Second")),
+            (Some("Guest"), Some("Artist"), Some("Third")),
+            (Some("One Hit"), None, Some("Single")),
+            (Some("One Hit"), None, Some("Single")),
+            (Some("One Hit"), None, Some("Single")),
+            (Some("One Hit"), None, Some("Single")),
+            (Some("Loose"), None, None),
+        ];
+        for (artist, album_artist, album) in plays {
+            db.log_play(&PlayRecord {
+                artist: artist.map(String::from),
+                album: album.map(String::from),
+                album_artist: album_artist.map(String::from),
+                song_duration_seconds: Some(180),
+                ..play(1702800000)
+            })?;
+        }
+
+        assert_eq!(
+            db.albums_per_artist(TimeInterval::AllTime, None, None)?,
+            vec![
+                ("Artist".to_string(), 3, 4),
+                ("One Hit".to_string(), 1, 4),
+                ("Loose".to_string(), 0, 1)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_listening_days() -> Result<()> {
+        // 2023-12-15 23:30 UTC, the first play is still on the 15th in New York
+        let night = 1702683000;
+        let db = MusicDb::new(":memory:")?.with_timezone(chrono_tz::America::New_York)?;
+
+        for (timestamp, duration) in [(night, 600), (night + 3600, 300), (night + 86400, 60)] {
+            db.log_play(&PlayRecord {
+                song_duration_seconds: Some(duration),
+                ..play(timestamp)
+            })?;
+        }
+
+        assert_eq!(
+            db.top_listening_days(TimeInterval::AllTime, None, None)?,
+            vec![
+                ("2023-12-15".to_string(), 15.0, 2),
+                ("2023-12-16".to_string(), 1.0, 1)
+            ]
+        );
+
+        let db = db.with_timezone(chrono_tz::UTC)?;
+        let days = db.top_listening_days(TimeInterval::AllTime, None, Some(1))?;
+        assert_eq!(days, vec![("2023-12-15".to_string(), 10.0, 1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_minutes_by_release_year() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        for date in [
+            Some("2015"),
+            Some("2015-03-02"),
+            Some("1969-09-26"),
+            None,
+            Some("unknown"),
+            Some("9999"),
+            Some("0000"),
+        ] {
+            db.log_play(&PlayRecord {
+                date: date.map(str::to_string),
+                song_duration_seconds: Some(60),
+                ..play(1702800000)
+            })?;
+        }
+
+        assert_eq!(
+            db.minutes_by_release_year(TimeInterval::AllTime, None)?,
+            vec![(Some(1969), 1.0), (Some(2015), 2.0), (None, 4.0)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_activity() -> Result<()> {
+        let db = MusicDb::new(":memory:")?.with_timezone(chrono_tz::UTC)?;
+        let stats = db.activity(TimeInterval::AllTime, None)?;
+        assert_eq!(
+            (stats.play_count, stats.active_days, stats.since),
+            (0, 0, None)
+        );
+
+        // two plays on one day, one on the next
+        for (timestamp, source) in [
+            (1702800000, None),
+            (1702803600, Some("office")),
+            (1702900000, None),
+        ] {
+            db.log_play(&PlayRecord {
+                song_duration_seconds: Some(180),
+                source: source.map(str::to_string),
+                ..play(timestamp)
+            })?;
+        }
+
+        let stats = db.activity(TimeInterval::AllTime, None)?;
+        assert_eq!(
+            (stats.play_count, stats.total_minutes, stats.active_days),
+            (3, 9.0, 2)
+        );
+        assert_eq!(stats.since, Some(1702800000));
+
+        let stats = db.activity(TimeInterval::AllTime, Some("office"))?;
+        assert_eq!((stats.play_count, stats.active_days), (1, 1));
+        assert_eq!(stats.since, Some(1702803600));
+
+        // the interval starts at its cutoff when there were plays before it
+        let stats = db.activity(TimeInterval::Since(1702850000), None)?;
+        assert_eq!((stats.play_count, stats.active_days), (1, 1));
+        assert_eq!(stats.since, Some(1702850000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_weekday_weekend_split() -> Result<()> {
+        // Friday 23:30 UTC is already Saturday in Tokyo
+        let friday_night = 1702683000;
+        let db = MusicDb::new(":memory:")?.with_timezone(chrono_tz::UTC)?;
+        db.log_play(&PlayRecord {
+            song_duration_seconds: Some(180),
+            ..play(friday_night)
+        })?;
+
+        let (weekdays, weekends) = db.weekday_weekend_split(TimeInterval::AllTime, None)?;
+        assert_eq!((weekdays.play_count, weekdays.total_minutes), (1, 3.0));
+        assert_eq!(weekends.play_count, 0);
+
+        let db = db.with_timezone(chrono_tz::Asia::Tokyo)?;
+        let (weekdays, weekends) = db.weekday_weekend_split(TimeInterval::AllTime, None)?;
+        assert_eq!(weekdays.play_count, 0);
+        assert_eq!((weekends.play_count, weekends.total_minutes), (1, 3.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_each_play_streams() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let records: Vec<_> = (0..3000)
+            .map(|i| PlayRecord {
+                title: Some(format!("Song {i}")),
+                other_tags: [("Genre".to_string(), vec!["Rock".to_string()])].into(),
+                song_duration_seconds: Some(180),
+                ..play(1702800000 + i)
+            })
+            .collect();
+        db.log_plays(&records)?;
+
+        let mut seen = 0;
+        db.for_each_play(None, None, |play| {
+            assert_eq!(play.timestamp, 1702800000 + seen);
+            assert_eq!(play.other_tags["Genre"], vec!["Rock"]);
+            seen += 1;
+            Ok::<_, rusqlite::Error>(())
+        })?;
+        assert_eq!(seen, 3000);
+
+        // plays are handed over as they are read, so stopping early skips the rest
+        let mut seen = 0;
+        let result = db.for_each_play(None, None, |_| {
+            seen += 1;
+            if seen == 10 {
+                return Err(rusqlite::Error::QueryReturnedNoRows);
+            }
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(seen, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cumulative_distinct_artists_by_month() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        // mid-month timestamps so the local timezone can't move them across months
+        let plays = [
+            (1705320000, "A", None),           // 2024-01-15
+            (1705406400, "B", Some("office")), // 2024-01-16
+            (1707998400, "A", None),           // 2024-02-15
+            (1713182400, "C", Some("office")), // 2024-04-15
+        ];
+        for (timestamp, artist, source) in plays {
+            db.log_play(&PlayRecord {
+                artist: Some(artist.to_string()),
+                source: source.map(str::to_string),
+                ..play(timestamp)
+            })?;
+        }
+
+        assert_eq!(
+            db.cumulative_distinct_artists_by_month(None)?,
+            vec![
+                ("2024-01".to_string(), 2),
+                ("2024-02".to_string(), 2),
+                ("2024-04".to_string(), 3),
+            ]
+        );
+        assert_eq!(
+            db.cumulative_distinct_artists_by_month(Some("office"))?,
+            vec![("2024-01".to_string(), 1), ("2024-04".to_string(), 2)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_play_retries_when_locked() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("mpd-wrapped-{}-locked.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let db = MusicDb::new(&path)?;
+        // fail immediately on a lock so only the retries can wait it out
+        db.conn.busy_timeout(Duration::ZERO)?;
+
+        let other = Connection::open(&path)?;
+        other.execute_batch("BEGIN EXCLUSIVE")?;
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            other.execute_batch("COMMIT")
+        });
+
+        let record = PlayRecord {
+            title: Some("Test Song".to_string()),
+            artist: Some("Test Artist".to_string()),
+            song_duration_seconds: Some(180),
+            ..play(1702800000)
+        };
+        let result = db.log_play(&record);
+        holder.join().unwrap()?;
+
+        assert!(result? > 0);
+        assert_eq!(get_plays(&db, None, None)?.len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_read_only() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("mpd-wrapped-{}-read-only.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let record = PlayRecord {
+            title: Some("Test Song".to_string()),
+            artist: Some("Test Artist".to_string()),
+            song_duration_seconds: Some(180),
+            ..play(1702800000)
+        };
+        MusicDb::new(&path)?.log_play(&record)?;
+
+        let db = MusicDb::open_read_only(&path)?;
+        assert!(db.is_read_only()?);
+        assert_eq!(
+            db.get_top_artists(TimeInterval::AllTime, None, None)?.len(),
+            1
+        );
+        assert_eq!(
+            db.top_listening_days(TimeInterval::AllTime, None, None)?
+                .len(),
+            1
+        );
+        assert!(db.log_play(&record).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_plays_constraints_migration() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        MIGRATIONS.to_version(&mut conn, 14)?;
+        conn.execute_batch(
+            "INSERT INTO plays (id, timestamp, title, artist, song_duration_seconds)
+             VALUES (1, 1702800000, 'Song', 'Artist', 180),
+                    (2, 1702800001, '', '', -5),
+                    (3, 0, 'Song', 'Artist', 180),
+                    (4, 1702800002, 'Song', 'Artist', 180);
+             INSERT INTO plays_other_tags (play_id, tag_name, tag_value)
+             VALUES (1, 'Genre', 'Rock'), (3, 'Genre', 'Rock');
+             INSERT INTO plays_artists (play_id, artist) VALUES (1, 'Artist'), (4, 'Artist');",
+        )?;
+
+        MIGRATIONS.to_latest(&mut conn)?;
+        let rows: Vec<(i64, Option<String>, Option<String>, i64)> = conn
+            .prepare("SELECT id, title, artist, song_duration_seconds FROM plays ORDER BY id")?
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(
+            rows,
+            vec![
+                (1, Some("Song".to_string()), Some("Artist".to_string()), 180),
+                (2, None, None, 0),
+                (3, Some("Song".to_string()), Some("Artist".to_string()), 180),
+                (4, Some("Song".to_string()), Some("Artist".to_string()), 180),
+            ]
+        );
+        // repaired rather than dropped, so it still counts
+        let timestamp: i64 =
+            conn.query_row("SELECT timestamp FROM plays WHERE id = 3", [], |row| {
+                row.get(0)
+            })?;
+        assert_eq!(timestamp, 1);
+        let tags: i64 = conn.query_row("SELECT COUNT(*) FROM plays_other_tags", [], |row| {
+            row.get(0)
+        })?;
+        assert_eq!(tags, 2);
+        let artists: i64 =
+            conn.query_row("SELECT COUNT(*) FROM plays_artists", [], |row| row.get(0))?;
+        assert_eq!(artists, 2);
+
+        for bad in [
+            "INSERT INTO plays (timestamp, title) VALUES (-1, 'Song')",
+            "INSERT INTO plays (timestamp, artist) VALUES (1702800000, '')",
+            "INSERT INTO plays (timestamp, song_duration_seconds) VALUES (1702800000, -1)",
+        ] {
+            assert!(conn.execute(bad, []).is_err(), "{bad}");
+        }
+        // ids aren't reused
+        conn.execute("INSERT INTO plays (timestamp) VALUES (1702800003)", [])?;
+        assert_eq!(conn.last_insert_rowid(), 5);
+
+        // logging a play with empty names stores them as missing
+        let db = MusicDb {
+            conn,
+            artist_separators: Vec::new(),
+        };
+        db.log_play(&PlayRecord {
+            title: Some(String::new()),
+            artist: Some(String::new()),
+            song_duration_seconds: Some(180),
+            ..play(1702800004)
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_play_round_trips() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let record = PlayRecord {
+            album: Some("Album".to_string()),
+            date: Some("2023".to_string()),
+            other_tags: HashMap::from([
+                (
+                    "Genre".to_string(),
+                    vec!["Rock".to_string(), "Pop".to_string()],
+                ),
+                ("Composer".to_string(), vec!["Someone".to_string()]),
+            ]),
+            song_duration_seconds: Some(180),
+            source: Some("office".to_string()),
+            random: Some(false),
+            file: Some("Artist/Album/01 Song.flac".to_string()),
+            ..play(1702800000)
+        };
+
+        let id = db.log_play(&record)?;
+        assert_eq!(db.get_play(id)?, Some(record));
+        assert_eq!(db.get_play(id + 1)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_songs_file() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let play = |timestamp, title: &str, file: Option<&str>| PlayRecord {
+            title: Some(title.to_string()),
+            song_duration_seconds: Some(180),
+            file: file.map(str::to_string),
+            ..play(timestamp)
+        };
+        db.log_play(&play(1702800000, "Moved", Some("old/Moved.flac")))?;
+        db.log_play(&play(1702800100, "Moved", Some("new/Moved.flac")))?;
+        db.log_play(&play(1702800200, "Moved", None))?;
+        db.log_play(&play(1702800300, "Old", None))?;
+
+        let songs = db.get_top_songs(TimeInterval::AllTime, None, None)?;
+        assert_eq!(songs.len(), 2);
+        // the latest known file, even if the latest play has none
+        assert_eq!(songs[0].title, "Moved");
+        assert_eq!(songs[0].file.as_deref(), Some("new/Moved.flac"));
+        assert_eq!(songs[0].play_count, 3);
+        assert_eq!(songs[1].file, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_sample() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let play = |timestamp, title: &str| PlayRecord {
+            title: Some(title.to_string()),
+            song_duration_seconds: Some(180),
+            file: Some(format!("{title}.flac")),
+            ..play(timestamp)
+        };
+        // a binged song shows up once
+        for i in 0..20 {
+            db.log_play(&play(1702800000 + i, "Binged"))?;
+        }
+        db.log_play(&play(1702800100, "Other"))?;
+        db.log_play(&play(1702700000, "Old"))?;
+
+        let sample = db.random_sample(10, TimeInterval::Since(1702800000), None)?;
+        let mut titles: Vec<_> = sample.iter().filter_map(|p| p.title.as_deref()).collect();
+        titles.sort();
+        assert_eq!(titles, vec!["Binged", "Other"]);
+        for play in &sample {
+            assert_eq!(
+                play.file,
+                play.title.as_ref().map(|title| format!("{title}.flac"))
+            );
+        }
+
+        assert_eq!(db.random_sample(1, TimeInterval::AllTime, None)?.len(), 1);
+        assert!(db.random_sample(0, TimeInterval::AllTime, None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reprocess_plays() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let record = PlayRecord {
+            artist: Some("A feat. B".to_string()),
+            other_tags: HashMap::from([
+                ("Genre".to_string(), vec!["Rock".to_string()]),
+                ("Track".to_string(), vec!["3".to_string()]),
+            ]),
+            album: Some("Album".to_string()),
+            song_duration_seconds: Some(180),
+            ..play(1702800000)
+        };
+        let id = db.log_play(&record)?;
+
+        // nothing changed, nothing to do
+        let filter = TagFilter {
+            drop: Vec::new(),
+            drop_sort_variants: true,
+        };
+        assert_eq!(db.reprocess_plays(&filter, false)?, 0);
+        assert_eq!(db.get_play(id)?, Some(record.clone()));
+
+        // dropping Track and splitting artists now apply to the old play
+        let db = db.with_artist_separators(vec!["feat.".to_string()]);
+        let filter = TagFilter {
+            drop: vec!["Track".to_string()],
+            drop_sort_variants: true,
+        };
+        // a dry run counts the change without making it
+        assert_eq!(db.reprocess_plays(&filter, true)?, 1);
+        assert_eq!(db.get_play(id)?, Some(record));
+        assert!(db
+            .get_top_split_artists(TimeInterval::AllTime, None, None)?
+            .iter()
+            .all(|artist| artist.artist_name == "A feat. B"));
+
+        assert_eq!(db.reprocess_plays(&filter, false)?, 1);
+        let play = db.get_play(id)?.unwrap();
+        assert_eq!(play.other_tags.keys().collect::<Vec<_>>(), vec!["Genre"]);
+        assert_eq!(play.artist.as_deref(), Some("A feat. B"));
+        let artists = db.get_top_split_artists(TimeInterval::AllTime, None, None)?;
+        assert_eq!(artists.len(), 2);
+
+        assert_eq!(db.reprocess_plays(&filter, false)?, 0);
+
+        // titles, artists and albums stay as recorded, even when their tags are dropped
+        let filter = TagFilter {
+            drop: ["Title", "Artist", "Album", "Track"]
+                .map(String::from)
+                .to_vec(),
+            drop_sort_variants: true,
+        };
+        assert_eq!(db.reprocess_plays(&filter, false)?, 0);
+        let play = db.get_play(id)?.unwrap();
+        assert_eq!(play.title.as_deref(), Some("Song"));
+        assert_eq!(play.artist.as_deref(), Some("A feat. B"));
+        assert_eq!(play.album.as_deref(), Some("Album"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_catch_up() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let counts = [("a.flac".to_string(), 3)];
+
+        db.record_catch_up(None, 1702800000, &counts, &[])?;
+        let (taken_at, baseline) = db.playcount_baseline(None)?.unwrap();
+        assert_eq!(taken_at, 1702800000);
+        assert_eq!(baseline, HashMap::from([("a.flac".to_string(), 3)]));
+
+        // a baseline that can't be stored takes the backfilled plays down with it
+        let backfill = PlayRecord {
+            file: Some("a.flac".to_string()),
+            source: Some("backfill".to_string()),
+            ..play(1702900000)
+        };
+        let duplicate = [("a.flac".to_string(), 4), ("a.flac".to_string(), 5)];
+        assert!(db
+            .record_catch_up(None, 1702900000, &duplicate, &[backfill])
+            .is_err());
+        assert!(get_plays(&db, None, None)?.is_empty());
+        assert_eq!(db.playcount_baseline(None)?.unwrap().0, 1702800000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diagnostics() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        assert_eq!(db.schema_version()?, MIGRATIONS_DIR.dirs().count());
+        assert!(!db.is_read_only()?);
+        assert_eq!(db.latest_play_timestamp()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_query_timestamp() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        assert_eq!(db.last_query_timestamp()?, None);
+
+        db.set_last_query_timestamp(1702800000)?;
+        db.set_last_query_timestamp(1702900000)?;
+        assert_eq!(db.last_query_timestamp()?, Some(1702900000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reactions() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let reaction = |timestamp, file: &str, reaction| ReactionRecord {
+            timestamp,
+            file: file.to_string(),
+            title: Some(file.to_string()),
+            artist: Some("Artist".to_string()),
+            album: None,
+            album_artist: None,
+            song_duration_seconds: Some(180),
+            source: None,
+            reaction,
+        };
+
+        db.record_reaction(&reaction(1702800000, "a.flac", Some(Reaction::Love)))?;
+        // loving again changes nothing
+        db.record_reaction(&reaction(1702800100, "a.flac", Some(Reaction::Love)))?;
+        db.record_reaction(&reaction(1702800200, "b.flac", Some(Reaction::Love)))?;
+        db.record_reaction(&reaction(1702800300, "c.flac", Some(Reaction::Ban)))?;
+
+        let loved = db.loved_tracks()?;
+        let loved: Vec<_> = loved
+            .iter()
+            .map(|track| (track.file.as_deref().unwrap(), track.timestamp))
+            .collect();
+        assert_eq!(loved, vec![("b.flac", 1702800200), ("a.flac", 1702800000)]);
+
+        // banning a loved song or taking back the love unloves it
+        db.record_reaction(&reaction(1702800400, "a.flac", Some(Reaction::Ban)))?;
+        db.record_reaction(&reaction(1702800500, "b.flac", None))?;
+        assert!(db.loved_tracks()?.is_empty());
+        let count: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM reactions", [], |row| row.get(0))?;
+        assert_eq!(count, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_queued() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let queued = QueuedRecord::from(crate::mpd::QueuedSong {
+            song: mpd::Song {
+                file: "a.flac".to_string(),
+                title: Some("Song".to_string()),
+                tags: vec![("Album".to_string(), "Album".to_string())],
+                duration: Some(Duration::from_secs(200)),
+                ..Default::default()
+            },
+            at: chrono::DateTime::from_timestamp(1702800000, 0).unwrap(),
+        });
+        db.log_queued(&queued)?;
+
+        let row: (i64, String, String, String, i64) = db.conn.query_row(
+            "SELECT timestamp, file, title, album, song_duration_seconds FROM queued",
+            [],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            },
+        )?;
+        assert_eq!(
+            row,
+            (
+                1702800000,
+                "a.flac".to_string(),
+                "Song".to_string(),
+                "Album".to_string(),
+                200
+            )
+        );
+        // queued songs aren't plays
+        assert!(get_plays(&db, None, None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_snapshots_are_capped() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+        let session = db.start_session(None, None, 1702800000)?;
+        let status = SongStatus {
+            state: mpd::State::Play,
+            random: false,
+            song: mpd::Song {
+                file: "a.flac".to_string(),
+                ..Default::default()
+            },
+            duration: Duration::from_secs(200),
+            elapsed: Duration::from_millis(1500),
+            queued: Vec::new(),
+            sticker_changes: Vec::new(),
+        };
+
+        for i in 0..MAX_STATUS_SNAPSHOTS + 5 {
+            db.record_status_snapshot(session, 1702800000 + i, &status)?;
+        }
+
+        let (count, oldest, elapsed): (i64, i64, f64) = db.conn.query_row(
+            "SELECT COUNT(*), MIN(timestamp), MAX(elapsed_seconds) FROM status_snapshots",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!(count, MAX_STATUS_SNAPSHOTS);
+        assert_eq!(oldest, 1702800005);
+        assert_eq!(elapsed, 1.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_listener_sessions() -> Result<()> {
+        let db = MusicDb::new(":memory:")?;
+
+        let first = db.start_session(None, Some("0.23.5"), 1702800000)?;
+        let second = db.start_session(Some("office"), None, 1702800100)?;
+        db.record_heartbeat(first, 1702800005)?;
+
+        let sessions: Vec<(i64, i64, Option<String>)> = db
+            .conn
+            .prepare(
+                "SELECT started_at, last_heartbeat, server_version
+                 FROM listener_sessions ORDER BY id",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_ne!(first, second);
+        assert_eq!(
+            sessions,
+            vec![
+                (1702800000, 1702800005, Some("0.23.5".to_string())),
+                (1702800100, 1702800100, None)
+            ]
+        );
 
         Ok(())
     }
@@ -0,0 +1,472 @@
+use crate::error::Result;
+use crate::mpd::SongStatus;
+use crate::persistence::model::{
+    ActivityStats, AlbumStats, ArtistComparison, ArtistStats, GenreStats, PlayRecord, QueuedRecord,
+    ReactionRecord, SongStats, TagFilter, TimeInterval, WeekdayStats,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// Storage for plays and the stats computed over them.
+///
+/// SQLite ([`MusicDb`](crate::persistence::MusicDb)) is the only implementation, the CLI only
+/// talks to this trait so that other backends can be added without touching it. The exception is
+/// `doctor`, which inspects the SQLite database itself.
+///
+/// Stats taking a `source` only count plays recorded from that source, or all plays if `None`.
+/// Top lists taking a `limit` return at most that many entries, or all of them if `None`.
+pub trait PlayStore {
+    /// Log a play record, returning its id
+    fn log_play(&self, record: &PlayRecord) -> Result<i64>;
+
+    /// Log a song added to the queue, kept apart from the plays, returning its id
+    fn log_queued(&self, record: &QueuedRecord) -> Result<i64>;
+
+    /// Record a song's reaction, replacing any earlier one. Repeating a reaction keeps the time it
+    /// was first given, and no reaction removes it.
+    fn record_reaction(&self, record: &ReactionRecord) -> Result<()>;
+
+    /// Get the loved songs, most recently loved first. The timestamp is when the song was loved.
+    fn loved_tracks(&self) -> Result<Vec<PlayRecord>>;
+
+    /// Re-extract all plays from their stored tags with `filter` and the current artist
+    /// separators, returning how many plays changed. Tags `filter` drops are deleted for good,
+    /// unless `dry_run` only counts the changes.
+    fn reprocess_plays(&self, filter: &TagFilter, dry_run: bool) -> Result<usize>;
+
+    /// Log several play records atomically
+    fn log_plays(&self, records: &[PlayRecord]) -> Result<()>;
+
+    /// Call `f` with each play with `start <= timestamp < end`, oldest first, without loading them
+    /// all at once. Missing bounds are open.
+    /// Stops at the first error `f` returns.
+    fn for_each_play(
+        &self,
+        start: Option<i64>,
+        end: Option<i64>,
+        f: &mut dyn FnMut(PlayRecord) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Pick up to `n` distinct songs played in the interval at random, each as its latest play
+    fn random_sample(
+        &self,
+        n: usize,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<Vec<PlayRecord>>;
+
+    fn get_top_artists(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ArtistStats>>;
+
+    fn get_top_songs(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<SongStats>>;
+
+    /// Top artists crediting each artist of a multi-artist play separately
+    fn get_top_split_artists(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ArtistStats>>;
+
+    /// Compare two artists side by side, matching their names case-insensitively
+    fn compare_artists(
+        &self,
+        a: &str,
+        b: &str,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<ArtistComparison>;
+
+    fn get_top_albums(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<AlbumStats>>;
+
+    /// Get top genres, marking those derived from the artist rather than the song's tags
+    fn get_top_genres(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<GenreStats>>;
+
+    /// Get every source plays were recorded from, with their play counts.
+    /// Plays recorded without a source are counted under `None`.
+    fn sources(&self) -> Result<Vec<(Option<String>, i64)>>;
+
+    /// Get artists with plays that have neither a Genre tag nor a derived genre
+    fn artists_missing_genre(&self) -> Result<Vec<String>>;
+
+    /// Get the cached genre lookup for an artist. `None` if the artist was never looked up,
+    /// `Some(None)` if it was but no genre was found.
+    fn cached_artist_genre(&self, artist: &str) -> Result<Option<Option<String>>>;
+
+    /// Cache the result of a genre lookup for an artist
+    fn cache_artist_genre(&self, artist: &str, genre: Option<&str>) -> Result<()>;
+
+    /// Set the derived genre on an artist's plays that have no Genre tag, returning how many changed
+    fn set_derived_genre(&self, artist: &str, genre: &str) -> Result<usize>;
+
+    /// Get the fraction of plays in the interval that were the first ever play of that song
+    fn novelty_ratio(&self, interval: TimeInterval, source: Option<&str>) -> Result<f64>;
+
+    /// The fraction of the last `months` calendar months (this one included) each artist was
+    /// played in, most consistent first
+    fn artist_consistency(
+        &self,
+        months: u32,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, f64)>>;
+
+    /// The all-time top `top_n` artists (all of them if `None`) that weren't played in the
+    /// interval, with their all-time play counts
+    fn absent_favorites(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        top_n: Option<usize>,
+    ) -> Result<Vec<(String, i64)>>;
+
+    /// How many different albums of each artist were played, as `(artist, albums, plays)`, most
+    /// albums first
+    fn albums_per_artist(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, i64, i64)>>;
+
+    /// Days with the most minutes listened, as `(YYYY-MM-DD, minutes, plays)`
+    fn top_listening_days(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, f64, i64)>>;
+
+    /// Minutes listened by release year, oldest first, with `None` for unknown or implausible years
+    fn minutes_by_release_year(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<Vec<(Option<i32>, f64)>>;
+
+    /// Minutes listened with random mode on and with it off, in that order, leaving out plays
+    /// recorded before random mode was tracked
+    fn minutes_by_random_mode(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<(f64, f64)>;
+
+    /// Plays, minutes and active calendar days in the interval, and when it effectively started
+    fn activity(&self, interval: TimeInterval, source: Option<&str>) -> Result<ActivityStats>;
+
+    /// Plays and minutes on weekdays and on weekends, in that order
+    fn weekday_weekend_split(
+        &self,
+        interval: TimeInterval,
+        source: Option<&str>,
+    ) -> Result<(WeekdayStats, WeekdayStats)>;
+
+    /// Get, for each month with plays, the number of distinct artists heard up to and including it
+    fn cumulative_distinct_artists_by_month(
+        &self,
+        source: Option<&str>,
+    ) -> Result<Vec<(String, i64)>>;
+
+    /// Get the Unix timestamp of the previous `query` invocation, if there was one
+    fn last_query_timestamp(&self) -> Result<Option<i64>>;
+
+    /// Record the Unix timestamp of a `query` invocation
+    fn set_last_query_timestamp(&self, timestamp: i64) -> Result<()>;
+
+    /// Get when the play count stickers were last caught up with for `source`, and their counts
+    /// then by file. `None` if they never were.
+    fn playcount_baseline(
+        &self,
+        source: Option<&str>,
+    ) -> Result<Option<(i64, HashMap<String, u64>)>>;
+
+    /// Log the plays a catch-up backfilled and replace the play count stickers caught up with for
+    /// `source`, as of `taken_at`, atomically
+    fn record_catch_up(
+        &self,
+        source: Option<&str>,
+        taken_at: i64,
+        counts: &[(String, u64)],
+        backfilled: &[PlayRecord],
+    ) -> Result<()>;
+
+    /// Count the plays of each file recorded from `source` since `since`
+    fn plays_by_file_since(&self, source: Option<&str>, since: i64)
+        -> Result<HashMap<String, u64>>;
+
+    /// Record the start of a listener session, returning its id
+    fn start_session(
+        &self,
+        source: Option<&str>,
+        server_version: Option<&str>,
+        timestamp: i64,
+    ) -> Result<i64>;
+
+    /// Record that the listener for a session was still alive at `timestamp`
+    fn record_heartbeat(&self, session: i64, timestamp: i64) -> Result<()>;
+
+    /// Record a raw status read from MPD during a session, keeping only the most recent ones
+    fn record_status_snapshot(
+        &self,
+        session: i64,
+        timestamp: i64,
+        status: &SongStatus,
+    ) -> Result<()>;
+}
+
+impl dyn PlayStore + '_ {
+    /// Start buffering play records, to be logged in batches
+    pub fn begin_buffered(&self, config: BufferConfig) -> BufferedWriter<'_> {
+        BufferedWriter::new(self, config)
+    }
+}
+
+/// When a [`BufferedWriter`] commits its pending records
+#[derive(Debug, Clone, Copy)]
+pub struct BufferConfig {
+    /// Commit once this many records are pending
+    pub max_records: usize,
+    /// Commit once this long has passed since the previous commit
+    pub max_interval: Duration,
+}
+
+impl Default for BufferConfig {
+    /// Commit every record as soon as it is pushed
+    fn default() -> Self {
+        Self {
+            max_records: 1,
+            max_interval: Duration::ZERO,
+        }
+    }
+}
+
+impl BufferConfig {
+    /// Buffer up to the given limits, committing when either is reached. A limit left unset is
+    /// unbounded, unless both are, which means the unbuffered default.
+    pub fn with_limits(max_records: Option<usize>, max_interval: Option<Duration>) -> Self {
+        if max_records.is_none() && max_interval.is_none() {
+            return Self::default();
+        }
+        Self {
+            max_records: max_records.unwrap_or(usize::MAX),
+            max_interval: max_interval.unwrap_or(Duration::MAX),
+        }
+    }
+}
+
+/// Accumulates play records and logs them in a single transaction.
+///
+/// Pending records are flushed when the writer is dropped, but anything still buffered when the
+/// process is killed without a chance to shut down is lost. That's the durability trade-off for
+/// throughput, hence the default [`BufferConfig`] commits every record.
+pub struct BufferedWriter<'a> {
+    store: &'a dyn PlayStore,
+    config: BufferConfig,
+    pending: Vec<PlayRecord>,
+    last_flush: Instant,
+}
+
+impl<'a> BufferedWriter<'a> {
+    pub fn new(store: &'a dyn PlayStore, config: BufferConfig) -> Self {
+        Self {
+            store,
+            config,
+            pending: Vec::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Buffer a play record, committing the buffer if it is full or due
+    pub fn push(&mut self, record: PlayRecord) -> Result<()> {
+        self.pending.push(record);
+
+        if self.pending.len() >= self.config.max_records {
+            self.flush()?;
+        }
+        self.flush_if_due()
+    }
+
+    /// Commit the pending records if the interval has passed since the previous commit, so they
+    /// don't wait for the next push when plays are few and far between
+    pub fn flush_if_due(&mut self) -> Result<()> {
+        if !self.pending.is_empty() && self.last_flush.elapsed() >= self.config.max_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Commit all pending play records
+    pub fn flush(&mut self) -> Result<()> {
+        match self.pending.as_slice() {
+            [] => {}
+            // the default, unbuffered case
+            [record] => {
+                self.store.log_play(record)?;
+            }
+            records => self.store.log_plays(records)?,
+        }
+        self.pending.clear();
+
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for BufferedWriter<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("failed to flush {} buffered plays: {e}", self.pending.len());
+        }
+    }
+}
+
+/// Tests any [`PlayStore`] should pass, run against each backend
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::MusicDb;
+
+    fn stores() -> Result<Vec<Box<dyn PlayStore>>> {
+        Ok(vec![Box::new(MusicDb::new(":memory:")?)])
+    }
+
+    fn all_plays(store: &dyn PlayStore) -> Result<Vec<PlayRecord>> {
+        let mut plays = Vec::new();
+        store.for_each_play(None, None, &mut |play| {
+            plays.push(play);
+            Ok(())
+        })?;
+        Ok(plays)
+    }
+
+    fn record(i: i64, artist: &str) -> PlayRecord {
+        PlayRecord {
+            timestamp: 1702800000 + i,
+            title: Some(format!("Song {}", i)),
+            artist: Some(artist.to_string()),
+            album: Some("Album".to_string()),
+            album_artist: None,
+            date: None,
+            other_tags: Default::default(),
+            song_duration_seconds: Some(120),
+            source: None,
+            random: None,
+            file: None,
+        }
+    }
+
+    #[test]
+    fn test_top_stats() -> Result<()> {
+        for store in stores()? {
+            store.log_plays(&[record(0, "A"), record(1, "A"), record(2, "B")])?;
+
+            let artists = store.get_top_artists(TimeInterval::AllTime, None, None)?;
+            assert_eq!(artists[0].artist_name, "A");
+            assert_eq!(artists[0].play_count, 2);
+            assert_eq!(artists[0].total_minutes, 4.0);
+
+            assert_eq!(
+                store
+                    .get_top_songs(TimeInterval::AllTime, None, None)?
+                    .len(),
+                3
+            );
+            assert_eq!(
+                store
+                    .get_top_albums(TimeInterval::AllTime, None, None)?
+                    .len(),
+                2
+            );
+            assert_eq!(all_plays(store.as_ref())?.len(), 3);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffered_writer() -> Result<()> {
+        for store in stores()? {
+            let config = BufferConfig {
+                max_records: 3,
+                max_interval: Duration::from_secs(60 * 60),
+            };
+            {
+                let mut writer = store.begin_buffered(config);
+                writer.push(record(0, "A"))?;
+                writer.push(record(1, "A"))?;
+                assert!(all_plays(store.as_ref())?.is_empty());
+
+                writer.push(record(2, "A"))?;
+                assert_eq!(all_plays(store.as_ref())?.len(), 3);
+
+                writer.push(record(3, "A"))?;
+            }
+
+            // dropping the writer flushes the rest
+            assert_eq!(all_plays(store.as_ref())?.len(), 4);
+
+            // an overdue buffer is committed without another push
+            let config = BufferConfig {
+                max_records: 100,
+                max_interval: Duration::from_millis(50),
+            };
+            let mut writer = store.begin_buffered(config);
+            writer.push(record(4, "A"))?;
+            writer.flush_if_due()?;
+            assert_eq!(all_plays(store.as_ref())?.len(), 4);
+
+            std::thread::sleep(Duration::from_millis(60));
+            writer.flush_if_due()?;
+            assert_eq!(all_plays(store.as_ref())?.len(), 5);
+            drop(writer);
+
+            // with only a count limit, time alone never commits
+            {
+                let config = BufferConfig::with_limits(Some(3), None);
+                let mut writer = store.begin_buffered(config);
+                writer.push(record(5, "A"))?;
+                writer.push(record(6, "A"))?;
+                writer.flush_if_due()?;
+                assert_eq!(all_plays(store.as_ref())?.len(), 5);
+
+                writer.push(record(7, "A"))?;
+                assert_eq!(all_plays(store.as_ref())?.len(), 8);
+            }
+
+            // with only a time limit, any number of records waits for it
+            {
+                let config = BufferConfig::with_limits(None, Some(Duration::from_secs(60 * 60)));
+                let mut writer = store.begin_buffered(config);
+                for timestamp in 8..18 {
+                    writer.push(record(timestamp, "A"))?;
+                }
+                writer.flush_if_due()?;
+                assert_eq!(all_plays(store.as_ref())?.len(), 8);
+            }
+            assert_eq!(all_plays(store.as_ref())?.len(), 18);
+        }
+
+        Ok(())
+    }
+}
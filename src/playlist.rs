@@ -0,0 +1,136 @@
+use crate::persistence::model::SongStats;
+use crate::persistence::PlayRecord;
+use std::io::{Result, Write};
+use std::path::Path;
+
+/// A song to put in a playlist
+#[derive(Debug)]
+pub struct Entry {
+    /// The song's file in MPD's music directory or its stream URL, songs without one are left out
+    pub file: Option<String>,
+    pub seconds: Option<u64>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+impl From<&PlayRecord> for Entry {
+    fn from(play: &PlayRecord) -> Self {
+        Entry {
+            file: play.file.clone(),
+            seconds: play.song_duration_seconds,
+            title: play.title.clone(),
+            artist: play.artist.clone(),
+        }
+    }
+}
+
+impl From<&SongStats> for Entry {
+    fn from(song: &SongStats) -> Self {
+        // the stats only know the song's total, its average play is as good as its length
+        let seconds = (song.play_count > 0)
+            .then(|| (song.total_minutes * 60.0 / song.play_count as f64).round() as u64);
+        Entry {
+            file: song.file.clone(),
+            seconds,
+            title: Some(song.title.clone()),
+            artist: Some(song.artist_name.clone()),
+        }
+    }
+}
+
+/// Write songs as an extended M3U playlist, returning how many made it in.
+///
+/// Files are relative to MPD's music directory, which is what playlists in MPD's playlist
+/// directory use. With `music_directory` they're made absolute for other players. Stream URLs are
+/// kept as they are.
+pub fn write_m3u<W: Write>(
+    mut out: W,
+    entries: &[Entry],
+    music_directory: Option<&Path>,
+) -> Result<usize> {
+    writeln!(out, "#EXTM3U")?;
+
+    let mut written = 0;
+    for entry in entries {
+        let Some(file) = &entry.file else {
+            continue;
+        };
+        // -1 is M3U for an unknown length
+        let seconds = entry
+            .seconds
+            .filter(|&seconds| seconds > 0)
+            .map_or(-1, |seconds| seconds as i64);
+        let title = match (&entry.artist, &entry.title) {
+            (Some(artist), Some(title)) => format!("{artist} - {title}"),
+            (None, Some(title)) => title.clone(),
+            (_, None) => file.clone(),
+        };
+        writeln!(out, "#EXTINF:{seconds},{title}")?;
+        match music_directory {
+            Some(directory) if !file.contains("://") => {
+                writeln!(out, "{}", directory.join(file).display())?
+            }
+            _ => writeln!(out, "{file}")?,
+        }
+        written += 1;
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(file: Option<&str>, seconds: Option<u64>) -> Entry {
+        Entry {
+            file: file.map(str::to_string),
+            seconds,
+            title: Some("Song".to_string()),
+            artist: Some("Artist".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_write_m3u() -> Result<()> {
+        let entries = [
+            entry(Some("Artist/Song.flac"), Some(180)),
+            entry(None, Some(200)),
+            entry(Some("http://radio.example/stream"), Some(0)),
+        ];
+
+        let mut out = Vec::new();
+        assert_eq!(write_m3u(&mut out, &entries, None)?, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#EXTM3U\n\
+             #EXTINF:180,Artist - Song\n\
+             Artist/Song.flac\n\
+             #EXTINF:-1,Artist - Song\n\
+             http://radio.example/stream\n"
+        );
+
+        // only files in the music directory become absolute
+        let mut out = Vec::new();
+        write_m3u(&mut out, &entries, Some(Path::new("/srv/music")))?;
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\n/srv/music/Artist/Song.flac\n"));
+        assert!(out.contains("\nhttp://radio.example/stream\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_from_song_stats() {
+        let song = SongStats {
+            title: "Song".to_string(),
+            artist_name: "Artist".to_string(),
+            play_count: 3,
+            total_minutes: 9.5,
+            file: Some("Artist/Song.flac".to_string()),
+        };
+        let entry = Entry::from(&song);
+        assert_eq!(entry.seconds, Some(190));
+        assert_eq!(entry.file.as_deref(), Some("Artist/Song.flac"));
+    }
+}
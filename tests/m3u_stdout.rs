@@ -0,0 +1,23 @@
+use std::process::Command;
+
+#[test]
+fn test_m3u_to_stdout_is_only_the_playlist() {
+    let home = std::env::temp_dir().join(format!("mpd-wrapped-m3u-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&home);
+
+    // a fresh database, so creating and migrating it is logged too
+    let output = Command::new(env!("CARGO_BIN_EXE_mpd-wrapped"))
+        .args(["query", "--all", "--songs", "--m3u", "-"])
+        .env("HOME", &home)
+        .env("XDG_DATA_HOME", home.join("data"))
+        .env("XDG_CONFIG_HOME", home.join("config"))
+        .env("RUST_LOG", "info")
+        .output()
+        .expect("failed to run mpd-wrapped");
+    let _ = std::fs::remove_dir_all(&home);
+
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("#EXTM3U\n"), "{stdout:?}");
+    assert!(!String::from_utf8_lossy(&output.stderr).is_empty());
+}